@@ -35,6 +35,15 @@ pub enum Error {
 
     /// Derived Key is too short
     DerivedKeyTooShort,
+
+    /// TVM pages were measured before the launch salt was set
+    SaltAfterTvmPages,
+
+    /// The launch salt was already set
+    SaltAlreadySet,
+
+    /// The measurement log has no room for another entry
+    MeasurementLogFull,
 }
 
 /// Custom attestation result.
@@ -174,4 +183,4 @@ pub mod manager;
 mod measurement;
 
 // Alias and be less mouthful.
-pub use manager::AttestationManager;
+pub use manager::{AttestationManager, MeasurementLog, MeasurementLogEntry};
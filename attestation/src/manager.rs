@@ -33,7 +33,7 @@ type CdiLen = U32;
 
 /// The TVM configuration data.
 /// This structure extends PCR3 when the TVM finalizes.
-#[derive(Clone, Default, Debug)]
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
 pub struct TvmConfiguration {
     // Initial program counter for the TVM.
     entry_pc: u64,
@@ -52,6 +52,18 @@ impl TvmConfiguration {
     }
 }
 
+/// What went into the TVM pages measurement so far.
+/// Tracked so that the launch salt is only ever at the start of its hash
+/// stream.
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+struct TvmPagesState {
+    // A launch salt was folded in.
+    salted: bool,
+
+    // At least one TVM page was measured.
+    measured: bool,
+}
+
 /// The attestation manager.
 pub struct AttestationManager<D: Digest, H: HmacImpl<D> = hmac::Hmac<D>> {
     // Measurement registers
@@ -70,10 +82,17 @@ pub struct AttestationManager<D: Digest, H: HmacImpl<D> = hmac::Hmac<D>> {
     // The data here goes into PCR3 when the TVM finalizes.
     tvm_config: RwLock<TvmConfiguration>,
 
+    // TVM pages measurement state.
+    tvm_pages: RwLock<TvmPagesState>,
+
     _pd: PhantomData<H>,
 }
 
 impl<'a, D: Digest, H: HmacImpl<D>> AttestationManager<D, H> {
+    /// The length, in bytes, of the measurement register digests.
+    /// Callers can use it to size buffers for `read_msmt_register()` output.
+    pub const DIGEST_LEN: usize = <D as OutputSizeUser>::OutputSize::USIZE;
+
     /// Create a new attestation manager.
     pub fn new(
         attestation_cdi: &'a [u8],
@@ -122,6 +141,7 @@ impl<'a, D: Digest, H: HmacImpl<D>> AttestationManager<D, H> {
                 .map_err(Error::DiceLayerBuild)?,
             vm_id,
             tvm_config: RwLock::new(Default::default()),
+            tvm_pages: RwLock::new(Default::default()),
             _pd: PhantomData,
         })
     }
@@ -136,12 +156,18 @@ impl<'a, D: Digest, H: HmacImpl<D>> AttestationManager<D, H> {
         bytes: &[u8],
         address: Option<u64>,
     ) -> Result<()> {
-        self.measurements
-            .write()
+        let mut measurements = self.measurements.write();
+        measurements
             .iter_mut()
             .find(|m| m.pcr_index == msmt_idx as u8)
             .ok_or(Error::InvalidMeasurementRegisterIndex(msmt_idx as usize))?
-            .extend(bytes, address)
+            .extend(bytes, address)?;
+        // Still holding the measurements lock, so that a salt can't sneak in
+        // behind this page.
+        if msmt_idx == TcgPcrIndex::TvmPage {
+            self.tvm_pages.write().measured = true;
+        }
+        Ok(())
     }
 
     /// Read a measurement register data.
@@ -159,29 +185,110 @@ impl<'a, D: Digest, H: HmacImpl<D>> AttestationManager<D, H> {
             .clone())
     }
 
+    /// Compare a measurement register against an expected digest.
+    /// The comparison runs in constant time, i.e. it always goes through all
+    /// the digest bytes regardless of where the first mismatch is, so that
+    /// a verifier doesn't leak how much of `expected` is correct through
+    /// timing.
+    pub fn verify_msmt_register(&self, msmt_idx: TcgPcrIndex, expected: &[u8]) -> Result<bool> {
+        let digest = self.read_msmt_register(msmt_idx)?;
+        if digest.len() != expected.len() {
+            return Ok(false);
+        }
+
+        let diff = digest
+            .iter()
+            .zip(expected)
+            .fold(0u8, |acc, (a, b)| acc | core::hint::black_box(a ^ b));
+        Ok(diff == 0)
+    }
+
     /// Extend the TVM pages measurement.
     /// This is a extend_msmt_register wrapper, where the address is not
     /// optional, and the measurement register is fixed to TvmPage.
+    /// TVM pages can only be measured while the TVM is being built, i.e.
+    /// before the manager is finalized.
     pub fn extend_tvm_page(&self, bytes: &[u8], address: u64) -> Result<()> {
-        self.extend_msmt_register(TcgPcrIndex::TvmPage, bytes, Some(address))
+        let result = self.extend_msmt_register(TcgPcrIndex::TvmPage, bytes, Some(address));
+        debug_assert!(!matches!(result, Err(Error::LockedMeasurementRegister(_))));
+        result
+    }
+
+    /// Fold a launch salt (e.g. a relying party chosen nonce) into the TVM
+    /// pages measurement, so that the final measurement can't be replayed
+    /// for a launch with a different salt.
+    /// The salt must be set before any TVM page is measured, so that it is
+    /// always at the start of the TVM pages hash stream, and it can only be
+    /// set once.
+    /// The salt length is measured on its own ahead of the salt. TVM page
+    /// extends always hash an address and a whole page, so that 8 byte
+    /// extend keeps the salt from passing for page data.
+    pub fn set_salt(&self, salt: &[u8]) -> Result<()> {
+        let mut measurements = self.measurements.write();
+        let mut tvm_pages = self.tvm_pages.write();
+        if tvm_pages.measured {
+            return Err(Error::SaltAfterTvmPages);
+        }
+        if tvm_pages.salted {
+            return Err(Error::SaltAlreadySet);
+        }
+
+        let msmt_idx = TcgPcrIndex::TvmPage;
+        let tvm_page_msmt = measurements
+            .iter_mut()
+            .find(|m| m.pcr_index == msmt_idx as u8)
+            .ok_or(Error::InvalidMeasurementRegisterIndex(msmt_idx as usize))?;
+        tvm_page_msmt.extend(&(salt.len() as u64).to_le_bytes(), None)?;
+        tvm_page_msmt.extend(salt, None)?;
+        tvm_pages.salted = true;
+        Ok(())
     }
 
     /// Extend the TVM configuration measurement.
     /// This is a extend_msmt_register wrapper, where the address is not
     /// optional, and the measurement register is fixed to TvmPage.
     pub fn extend_tvm_configuration(&self) -> Result<()> {
+        // Copy the configuration out rather than holding its lock while extending: the
+        // measurements lock is always taken first.
+        let tvm_config = self.tvm_config.read().clone();
         self.extend_msmt_register(
             TcgPcrIndex::TvmConfiguration,
-            &self.tvm_config.read().entry_pc.to_le_bytes(),
+            &tvm_config.entry_pc.to_le_bytes(),
             None,
         )?;
         self.extend_msmt_register(
             TcgPcrIndex::TvmConfiguration,
-            &self.tvm_config.read().entry_arg.to_le_bytes(),
+            &tvm_config.entry_arg.to_le_bytes(),
             None,
         )
     }
 
+    /// Reset all measurement registers and the TVM configuration back to their
+    /// initial state, so that the same manager can be reused for measuring a
+    /// new TVM build. The resulting registers are identical to the ones of a
+    /// freshly created manager.
+    /// Fails once the manager is finalized, as the DICE layers have already
+    /// been rolled from the previous measurements and the static registers
+    /// are locked.
+    pub fn reset_measurements(&self) -> Result<()> {
+        let mut measurements = self.measurements.write();
+        // Static registers are only locked by `finalize()`.
+        if let Some(m) = measurements
+            .iter()
+            .find(|m| m.static_measurement && !m.extensible)
+        {
+            return Err(Error::LockedMeasurementRegister(m.fwid_index));
+        }
+
+        for m in measurements.iter_mut() {
+            m.reset()
+        }
+        *self.tvm_config.write() = TvmConfiguration::default();
+        *self.tvm_pages.write() = TvmPagesState::default();
+
+        Ok(())
+    }
+
     fn attestation_tci(&self) -> GenericArray<u8, <D as OutputSizeUser>::OutputSize> {
         // The attestation TCI only includes the static measurements.
         let mut hasher = D::new();
@@ -232,6 +339,16 @@ impl<'a, D: Digest, H: HmacImpl<D>> AttestationManager<D, H> {
         Ok(())
     }
 
+    /// Finalize the manager and return the final TVM pages measurement in one step.
+    /// This is the common "build then attest" flow, and saves the caller from
+    /// reading the measurement register back separately after finalizing.
+    pub fn finish_measurement(
+        &self,
+    ) -> Result<GenericArray<u8, <D as OutputSizeUser>::OutputSize>> {
+        self.finalize()?;
+        self.read_msmt_register(TcgPcrIndex::TvmPage)
+    }
+
     /// Build a DER-formatted x.509 certificate from a CSR.
     /// The built certificate is signed by the TSM, and contains the provided
     /// subject and subject PKI.
@@ -281,3 +398,328 @@ impl<'a, D: Digest, H: HmacImpl<D>> AttestationManager<D, H> {
         Ok(caps)
     }
 }
+
+/// A batch of TVM pages recorded in a `MeasurementLog`.
+#[derive(Clone, Debug)]
+pub struct MeasurementLogEntry<D: Digest> {
+    /// The guest physical address of the first measured page.
+    pub address: u64,
+
+    /// The number of consecutive pages that were measured.
+    pub num_pages: u64,
+
+    /// The TVM pages measurement after these pages were measured.
+    pub digest: GenericArray<u8, <D as OutputSizeUser>::OutputSize>,
+}
+
+/// A log of the batches of pages that went into a TVM pages measurement, in measurement order.
+/// Together with the contents of the pages, it allows a verifier to replay the measurement and
+/// to tell which batch a mismatch comes from.
+pub struct MeasurementLog<D: Digest, const N: usize> {
+    entries: ArrayVec<MeasurementLogEntry<D>, N>,
+}
+
+impl<D: Digest, const N: usize> MeasurementLog<D, N> {
+    /// Create an empty measurement log.
+    pub fn new() -> Self {
+        Self {
+            entries: ArrayVec::new(),
+        }
+    }
+
+    /// Record that `num_pages` pages starting at `address` were just measured by `mgr`, along
+    /// with the resulting TVM pages measurement.
+    pub fn record<H: HmacImpl<D>>(
+        &mut self,
+        mgr: &AttestationManager<D, H>,
+        address: u64,
+        num_pages: u64,
+    ) -> Result<()> {
+        let digest = mgr.read_msmt_register(TcgPcrIndex::TvmPage)?;
+        self.entries
+            .try_push(MeasurementLogEntry {
+                address,
+                num_pages,
+                digest,
+            })
+            .map_err(|_| Error::MeasurementLogFull)
+    }
+
+    /// Returns true if no more entries can be recorded.
+    pub fn is_full(&self) -> bool {
+        self.entries.is_full()
+    }
+
+    /// Returns the recorded entries, in measurement order.
+    pub fn entries(&self) -> &[MeasurementLogEntry<D>] {
+        &self.entries
+    }
+}
+
+impl<D: Digest, const N: usize> Default for MeasurementLog<D, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::Sha384;
+
+    type AttestationSha384 = AttestationManager<Sha384>;
+
+    const ALL_MSMT_REGISTERS: [TcgPcrIndex; MSMT_REGISTERS] = [
+        TcgPcrIndex::PlatformCode,
+        TcgPcrIndex::PlatformConfiguration,
+        TcgPcrIndex::TvmPage,
+        TcgPcrIndex::TvmConfiguration,
+        TcgPcrIndex::RuntimePcr0,
+        TcgPcrIndex::RuntimePcr1,
+        TcgPcrIndex::RuntimePcr2,
+        TcgPcrIndex::RuntimePcr3,
+    ];
+
+    fn stub_manager() -> AttestationSha384 {
+        AttestationSha384::new(
+            b"TESTATTESTATIONCDI",
+            b"TESTSEALINGCDI",
+            1,
+            const_oid::db::rfc5912::ID_SHA_384,
+        )
+        .expect("creating attestation manager")
+    }
+
+    // Measures a small TVM: two pages, a runtime measurement and the current TVM configuration.
+    fn measure_stub_tvm(mgr: &AttestationSha384) {
+        mgr.extend_tvm_page(&[0xa5; 4096], 0x8000_0000).unwrap();
+        mgr.extend_tvm_page(&[0x5a; 4096], 0x8000_1000).unwrap();
+        mgr.extend_msmt_register(TcgPcrIndex::RuntimePcr0, b"runtime", None)
+            .unwrap();
+        mgr.extend_tvm_configuration().unwrap();
+    }
+
+    #[test]
+    fn reset_matches_new() {
+        let fresh = stub_manager();
+        fresh.set_salt(b"launch salt").unwrap();
+        measure_stub_tvm(&fresh);
+
+        let reused = stub_manager();
+        reused.set_salt(b"previous salt").unwrap();
+        reused.extend_tvm_page(&[0xff; 4096], 0x9000_0000).unwrap();
+        reused
+            .extend_msmt_register(TcgPcrIndex::RuntimePcr3, b"previous build", None)
+            .unwrap();
+        reused.set_epc(0x9000_0000);
+        reused.set_arg(0x1234);
+        reused.reset_measurements().unwrap();
+        // Nothing of the previous build is left, including its salt.
+        let new = stub_manager();
+        assert_eq!(*reused.tvm_config.read(), *new.tvm_config.read());
+        assert_eq!(*reused.tvm_pages.read(), *new.tvm_pages.read());
+        reused.set_salt(b"launch salt").unwrap();
+        measure_stub_tvm(&reused);
+
+        for idx in ALL_MSMT_REGISTERS {
+            assert_eq!(
+                fresh.read_msmt_register(idx).unwrap(),
+                reused.read_msmt_register(idx).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn reset_after_finalize() {
+        let mgr = stub_manager();
+        measure_stub_tvm(&mgr);
+        mgr.finalize().unwrap();
+        let tvm_pages = mgr.read_msmt_register(TcgPcrIndex::TvmPage).unwrap();
+
+        assert!(matches!(
+            mgr.reset_measurements(),
+            Err(Error::LockedMeasurementRegister(_))
+        ));
+        // Nothing was reset, and the static registers are still locked.
+        assert_eq!(
+            mgr.read_msmt_register(TcgPcrIndex::TvmPage).unwrap(),
+            tvm_pages
+        );
+        assert!(matches!(
+            mgr.extend_msmt_register(TcgPcrIndex::TvmPage, &[0; 4096], Some(0x8000_2000)),
+            Err(Error::LockedMeasurementRegister(_))
+        ));
+    }
+
+    #[test]
+    fn salt() {
+        let measure_salted = |salt: &[u8]| {
+            let mgr = stub_manager();
+            mgr.set_salt(salt).unwrap();
+            measure_stub_tvm(&mgr);
+            mgr.read_msmt_register(TcgPcrIndex::TvmPage).unwrap()
+        };
+        let unsalted = stub_manager();
+        measure_stub_tvm(&unsalted);
+        let unsalted = unsalted.read_msmt_register(TcgPcrIndex::TvmPage).unwrap();
+
+        assert_eq!(measure_salted(b"nonce"), measure_salted(b"nonce"));
+        assert_ne!(measure_salted(b"nonce"), measure_salted(b"other nonce"));
+        assert_ne!(measure_salted(b"nonce"), unsalted);
+        assert_ne!(measure_salted(b""), unsalted);
+
+        // A salt made up of a page and its address doesn't measure the same
+        // as that page.
+        let page = [0xa5; 4096];
+        let page_addr: u64 = 0x8000_0000;
+        let mut page_salt = [0; 8 + 4096];
+        page_salt[..8].copy_from_slice(&page_addr.to_le_bytes());
+        page_salt[8..].copy_from_slice(&page);
+        let salted = stub_manager();
+        salted.set_salt(&page_salt).unwrap();
+        let paged = stub_manager();
+        paged.extend_tvm_page(&page, page_addr).unwrap();
+        assert_ne!(
+            salted.read_msmt_register(TcgPcrIndex::TvmPage).unwrap(),
+            paged.read_msmt_register(TcgPcrIndex::TvmPage).unwrap()
+        );
+    }
+
+    #[test]
+    fn salt_ordering() {
+        let mgr = stub_manager();
+        mgr.set_salt(b"nonce").unwrap();
+        assert!(matches!(mgr.set_salt(b"nonce"), Err(Error::SaltAlreadySet)));
+        measure_stub_tvm(&mgr);
+        assert!(matches!(
+            mgr.set_salt(b"nonce"),
+            Err(Error::SaltAfterTvmPages)
+        ));
+
+        // Without a salt, measuring a page still closes the window for one.
+        let mgr = stub_manager();
+        mgr.extend_tvm_page(&[0xa5; 4096], 0x8000_0000).unwrap();
+        assert!(matches!(
+            mgr.set_salt(b"nonce"),
+            Err(Error::SaltAfterTvmPages)
+        ));
+
+        // A reset starts a new TVM build, which may have its own salt.
+        mgr.reset_measurements().unwrap();
+        mgr.set_salt(b"nonce").unwrap();
+    }
+
+    #[test]
+    fn digest_len() {
+        assert_eq!(AttestationSha384::DIGEST_LEN, 48);
+        let mgr = stub_manager();
+        for idx in ALL_MSMT_REGISTERS {
+            assert_eq!(
+                mgr.read_msmt_register(idx).unwrap().len(),
+                AttestationSha384::DIGEST_LEN
+            );
+        }
+    }
+
+    #[test]
+    fn verify_msmt_register() {
+        let mgr = stub_manager();
+        measure_stub_tvm(&mgr);
+        let digest = mgr.read_msmt_register(TcgPcrIndex::TvmPage).unwrap();
+
+        assert!(mgr
+            .verify_msmt_register(TcgPcrIndex::TvmPage, &digest)
+            .unwrap());
+        // A difference in the last byte is caught like any other.
+        let mut wrong = digest.clone();
+        *wrong.last_mut().unwrap() ^= 1;
+        assert!(!mgr
+            .verify_msmt_register(TcgPcrIndex::TvmPage, &wrong)
+            .unwrap());
+        assert!(!mgr
+            .verify_msmt_register(TcgPcrIndex::TvmPage, &digest[1..])
+            .unwrap());
+        // Other registers don't match the TVM pages digest.
+        assert!(!mgr
+            .verify_msmt_register(TcgPcrIndex::TvmConfiguration, &digest)
+            .unwrap());
+    }
+
+    #[test]
+    fn finish_measurement() {
+        let mgr = stub_manager();
+        measure_stub_tvm(&mgr);
+        let digest = mgr.finish_measurement().unwrap();
+        assert_eq!(
+            digest,
+            mgr.read_msmt_register(TcgPcrIndex::TvmPage).unwrap()
+        );
+
+        // The finished register can no longer be extended.
+        assert!(matches!(
+            mgr.extend_msmt_register(TcgPcrIndex::TvmPage, &[0; 4096], Some(0x8000_2000)),
+            Err(Error::LockedMeasurementRegister(_))
+        ));
+        assert_eq!(
+            mgr.read_msmt_register(TcgPcrIndex::TvmPage).unwrap(),
+            digest
+        );
+    }
+
+    // Deterministic, per-page contents for measured pages.
+    fn page_contents(address: u64) -> [u8; 4096] {
+        [(address >> 12) as u8; 4096]
+    }
+
+    // Measures the `num_pages` pages at `address` into `mgr`.
+    fn measure_pages(mgr: &AttestationSha384, address: u64, num_pages: u64) {
+        for addr in (address..).step_by(4096).take(num_pages as usize) {
+            mgr.extend_tvm_page(&page_contents(addr), addr).unwrap();
+        }
+    }
+
+    #[test]
+    fn replay_measurement_log() {
+        let mgr = stub_manager();
+        let mut log = MeasurementLog::<Sha384, 2>::new();
+        for (address, num_pages) in [(0x8000_0000, 3), (0x8020_0000, 2)] {
+            measure_pages(&mgr, address, num_pages);
+            log.record(&mgr, address, num_pages).unwrap();
+        }
+        assert!(log.is_full());
+        assert!(matches!(
+            log.record(&mgr, 0x8040_0000, 1),
+            Err(Error::MeasurementLogFull)
+        ));
+        assert_eq!(log.entries().len(), 2);
+
+        // Replaying the log reproduces each intermediate and the final measurement.
+        let replayed = stub_manager();
+        for entry in log.entries() {
+            measure_pages(&replayed, entry.address, entry.num_pages);
+            assert!(replayed
+                .verify_msmt_register(TcgPcrIndex::TvmPage, &entry.digest)
+                .unwrap());
+        }
+        assert_eq!(
+            replayed.read_msmt_register(TcgPcrIndex::TvmPage).unwrap(),
+            mgr.read_msmt_register(TcgPcrIndex::TvmPage).unwrap()
+        );
+
+        // Any difference in the pages shows up from the entry they're in onwards.
+        let tampered = stub_manager();
+        let first = &log.entries()[0];
+        measure_pages(&tampered, first.address, first.num_pages);
+        assert!(tampered
+            .verify_msmt_register(TcgPcrIndex::TvmPage, &first.digest)
+            .unwrap());
+        let second = &log.entries()[1];
+        tampered
+            .extend_tvm_page(&[0; 4096], second.address)
+            .unwrap();
+        measure_pages(&tampered, second.address + 4096, second.num_pages - 1);
+        assert!(!tampered
+            .verify_msmt_register(TcgPcrIndex::TvmPage, &second.digest)
+            .unwrap());
+    }
+}
@@ -160,6 +160,13 @@ impl<D: Digest> MeasurementRegister<D> {
         self.static_measurement.then(|| self.extensible = false);
     }
 
+    // Resets the register digest back to its freshly built, all-zero state.
+    // The register layout (indexes, hash algorithm, static and stable
+    // properties) and its lock state are left untouched.
+    pub fn reset(&mut self) {
+        self.digest = GenericArray::default();
+    }
+
     pub fn to_sbi_descriptor(&self) -> MeasurementRegisterDescriptor {
         MeasurementRegisterDescriptor::new(
             self.tcb_layer.unwrap_or(0),
@@ -0,0 +1,143 @@
+// Copyright (c) 2022 by Rivos Inc.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use riscv_pages::GuestPageAddr;
+
+/// The number of recent page faults that are remembered for fault loop detection.
+pub const FAULT_HISTORY_LEN: usize = 32;
+
+/// The number of times a page may fault without the VM's address space being updated before the
+/// vCPU is considered to be stuck in a fault loop.
+pub const MAX_REPEATED_FAULTS: usize = 16;
+
+/// Ring buffer of the page faults recently taken by a vCPU that must be resolved by its host. Used
+/// to detect a vCPU that keeps faulting on the same page without any forward progress being made.
+///
+/// Progress is tracked using a generation number for the VM's address space, which must change
+/// whenever pages are mapped into it. Faults recorded at an older generation may have since been
+/// resolved and are forgotten.
+///
+/// The history is kept per vCPU rather than per VM: any number of vCPUs may legitimately fault on
+/// the same page before the host gets around to mapping it.
+#[derive(Clone, Debug)]
+pub struct FaultHistory {
+    faults: [Option<GuestPageAddr>; FAULT_HISTORY_LEN],
+    next: usize,
+    generation: u64,
+}
+
+impl FaultHistory {
+    /// Creates a new, empty `FaultHistory`.
+    pub fn new() -> Self {
+        Self {
+            faults: [None; FAULT_HISTORY_LEN],
+            next: 0,
+            generation: 0,
+        }
+    }
+
+    /// Records a fault on `page_addr` taken while the address space was at `generation`. Returns
+    /// true if `page_addr` has faulted more than `MAX_REPEATED_FAULTS` times without the address
+    /// space changing, i.e. if the vCPU is stuck in a fault loop.
+    pub fn record(&mut self, page_addr: GuestPageAddr, generation: u64) -> bool {
+        if generation != self.generation {
+            self.faults = [None; FAULT_HISTORY_LEN];
+            self.next = 0;
+            self.generation = generation;
+        }
+        self.faults[self.next] = Some(page_addr);
+        self.next = (self.next + 1) % FAULT_HISTORY_LEN;
+        let repeats = self
+            .faults
+            .iter()
+            .filter(|&&f| f == Some(page_addr))
+            .count();
+        repeats > MAX_REPEATED_FAULTS
+    }
+}
+
+impl Default for FaultHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The possible sources of a guest page fault.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageFaultType {
+    /// A page fault taken when accessing a confidential memory region. The host may handle these
+    /// faults by inserting a confidential page into the guest's address space.
+    Confidential,
+    /// A page fault taken when accessing a shared memory region. The host may handle these faults
+    /// by inserting a page into the guest's address space.
+    Shared,
+    /// A page fault taken to an emulated MMIO page.
+    Mmio,
+    /// A page fault taken to an IMSIC guest interrupt file page.
+    Imsic,
+    /// A page fault taken when accessing memory outside of any valid region of guest physical
+    /// address space. These faults are not resolvable.
+    Unmapped,
+}
+
+impl PageFaultType {
+    /// Returns the type of a fault to the same memory taken by an implicit access of the guest's
+    /// VS-stage page table walk, rather than by the faulting instruction itself. Walk faults to
+    /// confidential or shared memory are resolved like any other, by mapping the page and
+    /// retrying. A guest page table can't live in emulated MMIO or IMSIC pages though, and the
+    /// faulting instruction can't be emulated as an access to them, so those aren't resolvable.
+    pub fn for_guest_pt_walk(self) -> Self {
+        use PageFaultType::*;
+        match self {
+            Mmio | Imsic => Unmapped,
+            pf => pf,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use riscv_pages::{PageAddr, PageOwnerId, RawAddr};
+
+    fn guest_page(addr: u64) -> GuestPageAddr {
+        PageAddr::new(RawAddr::guest(addr, PageOwnerId::host())).unwrap()
+    }
+
+    #[test]
+    fn repeated_fault_loops() {
+        let mut history = FaultHistory::new();
+        let addr = guest_page(0x8000_0000);
+        for _ in 0..MAX_REPEATED_FAULTS {
+            assert!(!history.record(addr, 0));
+        }
+        assert!(history.record(addr, 0));
+        // Keeps reporting the loop until the address space is updated.
+        assert!(history.record(addr, 0));
+        assert!(!history.record(addr, 1));
+    }
+
+    #[test]
+    fn guest_pt_walk_faults() {
+        use PageFaultType::*;
+        // Walks through confidential or shared memory are resolved by mapping the page.
+        assert_eq!(Confidential.for_guest_pt_walk(), Confidential);
+        assert_eq!(Shared.for_guest_pt_walk(), Shared);
+        // Walks through MMIO or IMSIC pages can't be emulated.
+        assert_eq!(Mmio.for_guest_pt_walk(), Unmapped);
+        assert_eq!(Imsic.for_guest_pt_walk(), Unmapped);
+        assert_eq!(Unmapped.for_guest_pt_walk(), Unmapped);
+    }
+
+    #[test]
+    fn distinct_faults_dont_loop() {
+        let mut history = FaultHistory::new();
+        for i in 0..(4 * FAULT_HISTORY_LEN as u64) {
+            assert!(!history.record(guest_page(0x8000_0000 + (i % 2) * 0x1000), 0));
+        }
+        for i in 0..(4 * FAULT_HISTORY_LEN as u64) {
+            assert!(!history.record(guest_page(0x9000_0000 + i * 0x1000), 0));
+        }
+    }
+}
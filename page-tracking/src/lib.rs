@@ -28,6 +28,9 @@ extern crate alloc;
 
 /// `Page`-backed collections resembling those in the standard library.
 pub mod collections;
+/// Implements the `PageFaultType` and `FaultHistory` types, used for classifying guest page faults
+/// and detecting vCPUs stuck faulting on the same page.
+pub mod fault_history;
 mod hw_mem_map;
 mod page_info;
 /// Implements a linked-list of pages using `PageTracker`.
@@ -37,6 +40,7 @@ pub mod page_tracker;
 /// Implements a `TlbVersion` type, used for tracking the progress of TLB shootdowns.
 pub mod tlb_version;
 
+pub use fault_history::{FaultHistory, PageFaultType};
 pub use hw_mem_map::Error as MemMapError;
 pub use hw_mem_map::Result as MemMapResult;
 pub use hw_mem_map::{HwMemMap, HwMemMapBuilder, HwMemRegion, HwMemRegionType, HwReservedMemType};
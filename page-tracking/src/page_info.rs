@@ -427,9 +427,8 @@ impl PageMap {
         let total_pages = mem_map
             .regions()
             .fold(0, |pages, r| pages + r.size() / PageSize::Size4k as u64);
-        let page_map_size =
-            PageSize::Size4k.round_up(total_pages * core::mem::size_of::<PageInfo>() as u64);
-        let page_map_pages = page_map_size / PageSize::Size4k as u64;
+        let page_map_pages = Self::tracking_overhead(total_pages);
+        let page_map_size = page_map_pages * PageSize::Size4k as u64;
 
         // Find a space for the page map.
         let page_map_region = mem_map
@@ -460,6 +459,12 @@ impl PageMap {
         page_map
     }
 
+    /// Returns the number of 4kB pages consumed by the `PageMap` itself when tracking
+    /// `total_pages` pages of memory, as reserved by `build_from()`.
+    pub fn tracking_overhead(total_pages: u64) -> u64 {
+        PageSize::num_4k_pages(total_pages * core::mem::size_of::<PageInfo>() as u64)
+    }
+
     /// Constructs an empty `PageMap` from an existing vector of `PageInfo` structs.
     fn new(pages: RawPageVec<PageInfo>) -> Self {
         Self {
@@ -630,6 +635,39 @@ mod tests {
         RawPageVec::from(SequentialPages::from(page))
     }
 
+    #[test]
+    fn tracking_overhead_matches_build() {
+        const NUM_PAGES: u64 = 1024;
+        let backing_mem = vec![0u8; ((NUM_PAGES + 1) * PageSize::Size4k as u64) as usize];
+        let aligned_pointer = unsafe {
+            // Not safe - just a test
+            backing_mem
+                .as_ptr()
+                .add(backing_mem.as_ptr().align_offset(4096))
+        };
+        let mut mem_map = unsafe {
+            // Not safe - just a test. The backing memory is leaked so that the page map built in
+            // it lives until the test exits.
+            HwMemMapBuilder::new(PageSize::Size4k as u64)
+                .add_memory_region(
+                    RawAddr::supervisor(aligned_pointer as u64),
+                    NUM_PAGES * PageSize::Size4k as u64,
+                )
+                .unwrap()
+                .build()
+        };
+        core::mem::forget(backing_mem);
+        let _ = PageMap::build_from(&mut mem_map);
+
+        let reserved_pages: u64 = mem_map
+            .regions()
+            .filter(|r| r.region_type() == HwMemRegionType::Reserved(HwReservedMemType::PageMap))
+            .map(|r| r.size() / PageSize::Size4k as u64)
+            .sum();
+        assert!(reserved_pages > 1);
+        assert_eq!(reserved_pages, PageMap::tracking_overhead(NUM_PAGES));
+    }
+
     #[test]
     fn indexing() {
         let pages = stub_page_vec();
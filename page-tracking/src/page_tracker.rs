@@ -83,6 +83,10 @@ impl PageTrackerInner {
 /// This struct wraps the list of all memory pages and active guests. It can be cloned and passed to
 /// other compontents that need access to page state. Once created, there is no way to free the
 /// backing page list. That page list is needed for the lifetime of the system.
+///
+/// A `PageTracker` is only a handle to the shared, statically-allocated page state: cloning it is
+/// a pointer copy and every clone observes (and makes) the same ownership changes. There's no need
+/// to avoid clones, e.g. to get a tracker that outlives a borrow of the original.
 #[derive(Clone)]
 pub struct PageTracker {
     inner: StaticPageRef<Mutex<PageTrackerInner>>,
@@ -351,6 +355,23 @@ impl PageTracker {
         }
     }
 
+    /// Returns an iterator over the addresses in `pages` that aren't already owned by `owner`. This
+    /// allows a range of pages to be (re-)assigned idempotently by skipping over the pages that were
+    /// assigned to `owner` by a previous, possibly interrupted, pass.
+    pub fn filter_unowned<'a, I>(
+        &'a self,
+        pages: I,
+        owner: PageOwnerId,
+    ) -> impl Iterator<Item = SupervisorPageAddr> + 'a
+    where
+        I: IntoIterator<Item = SupervisorPageAddr>,
+        I::IntoIter: 'a,
+    {
+        pages
+            .into_iter()
+            .filter(move |&addr| !self.is_owned(addr, owner))
+    }
+
     /// Returns true if and only if `addr` is a "Mapped" page owned by `owner` with type `mem_type`.
     pub fn is_mapped_page(
         &self,
@@ -554,6 +575,12 @@ impl HypPageAlloc {
         }
     }
 
+    /// Returns the number of bytes remaining in the system. Like `pages_remaining()`, this may
+    /// include reserved pages.
+    pub fn bytes_remaining(&self) -> u64 {
+        self.pages_remaining() * PageSize::Size4k as u64
+    }
+
     // Core allocator function. Finds `count` contiguous pages with the requested alignment from the
     // system map. Sets the hypervisor as the owner and `page_state` as the state, for the returned
     // pages and for any pages consumed for alignment purposes.
@@ -678,6 +705,7 @@ impl HypPageAlloc {
 mod tests {
     use super::*;
     use crate::HwMemMapBuilder;
+    use alloc::vec::Vec;
     use riscv_pages::RawAddr;
 
     fn stub_hyp_mem() -> HypPageAlloc {
@@ -746,6 +774,19 @@ mod tests {
         assert_eq!(range.base().bits() & (16 * 1024 - 1), 0);
     }
 
+    #[test]
+    fn hyp_mem_remaining() {
+        let mut hyp_mem = stub_hyp_mem();
+        let before = hyp_mem.pages_remaining();
+        assert_eq!(hyp_mem.bytes_remaining(), before * PageSize::Size4k as u64);
+        let _ = hyp_mem.take_pages(3, PageSize::Size4k as u64);
+        assert_eq!(hyp_mem.pages_remaining(), before - 3);
+        assert_eq!(
+            hyp_mem.bytes_remaining(),
+            (before - 3) * PageSize::Size4k as u64
+        );
+    }
+
     #[test]
     fn hyp_mem_drain() {
         let hyp_mem = stub_hyp_mem();
@@ -755,6 +796,46 @@ mod tests {
         assert!((host_pages.len() as u64) < remaining);
     }
 
+    #[test]
+    fn filter_unowned_pages() {
+        let (page_tracker, mut host_pages) = stub_page_tracker();
+        let guest_id = page_tracker.add_active_guest().unwrap();
+        let pages: Vec<Page<ConvertedClean>> = (0..4).map(|_| host_pages.pop().unwrap()).collect();
+        let addrs: Vec<SupervisorPageAddr> = pages.iter().map(|p| p.addr()).collect();
+        for page in pages.into_iter().step_by(2) {
+            page_tracker
+                .assign_page_for_internal_state(page, guest_id)
+                .unwrap();
+        }
+
+        let unowned: Vec<SupervisorPageAddr> = page_tracker
+            .filter_unowned(addrs.iter().copied(), guest_id)
+            .collect();
+        assert_eq!(unowned, vec![addrs[1], addrs[3]]);
+    }
+
+    #[test]
+    fn internal_state_page_owner() {
+        let (page_tracker, mut host_pages) = stub_page_tracker();
+        let guest_id = page_tracker.add_active_guest().unwrap();
+        let other_id = page_tracker.add_active_guest().unwrap();
+        let page = page_tracker
+            .assign_page_for_internal_state(host_pages.pop().unwrap(), guest_id)
+            .unwrap();
+        let foreign = page_tracker
+            .assign_page_for_internal_state(host_pages.pop().unwrap(), other_id)
+            .unwrap();
+        let mapped = page_tracker
+            .assign_page_for_mapping(host_pages.pop().unwrap(), guest_id)
+            .unwrap();
+
+        assert!(page_tracker.is_internal_state_page(page.addr(), guest_id));
+        // Pages owned by another VM, or owned but not holding internal state, are refused.
+        assert!(!page_tracker.is_internal_state_page(foreign.addr(), guest_id));
+        assert!(!page_tracker.is_internal_state_page(mapped.addr(), guest_id));
+        assert!(!page_tracker.is_internal_state_page(page.addr(), PageOwnerId::host()));
+    }
+
     #[test]
     fn drop_one_page_tracker_ref() {
         let (page_tracker, _host_mem) = stub_page_tracker();
@@ -768,4 +849,21 @@ mod tests {
 
         assert_eq!(page_tracker.inner.lock().active_guests.len(), 1);
     }
+
+    #[test]
+    fn cloned_tracker_shares_state() {
+        let (page_tracker, mut host_pages) = stub_page_tracker();
+        let clone = page_tracker.clone();
+        let guest_id = clone.add_active_guest().unwrap();
+        let page = host_pages.pop().unwrap();
+        let addr = page.addr();
+
+        // Ownership changes made through one handle are visible through the other.
+        page_tracker
+            .assign_page_for_internal_state(page, guest_id)
+            .unwrap();
+        assert!(clone.is_owned(addr, guest_id));
+        clone.release_page_by_addr(addr, guest_id).unwrap();
+        assert!(!page_tracker.is_owned(addr, guest_id));
+    }
 }
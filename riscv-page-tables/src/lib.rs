@@ -52,8 +52,8 @@ pub use page_table::Error as PageTableError;
 pub use page_table::Result as PageTableResult;
 pub use page_table::{
     FirstStageMapper, FirstStagePageTable, FirstStagePagingMode, GuestStageMapper,
-    GuestStagePageTable, GuestStagePagingMode, PagingMode,
+    GuestStagePageTable, GuestStagePagingMode, LeafInfo, PageTableStatistics, PagingMode,
 };
-pub use pte::{PteFieldBits, PteLeafPerms};
+pub use pte::{AccessType, FaultError, PteFieldBits, PteLeafPerms};
 pub use sv48::Sv48;
 pub use sv48x4::Sv48x4;
@@ -2,8 +2,10 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::pte::{Pte, PteFieldBits, PteLeafPerms};
+use crate::pte::{AccessType, FaultError, Pte, PteFieldBit, PteFieldBits, PteLeafPerms};
 use core::marker::PhantomData;
+use core::num::NonZeroU64;
+use core::ops::ControlFlow;
 use page_tracking::PageTracker;
 use riscv_pages::*;
 use spin::Mutex;
@@ -53,6 +55,12 @@ pub enum Error {
     PredicateFailed,
     /// An address range causes overflow.
     AddressOverflow,
+    /// The operation was cancelled by the caller before it completed.
+    Cancelled,
+    /// The pages to map ran out before the requested range was filled.
+    PagesExhausted,
+    /// A page for holding PTEs wasn't an internal state page of the table's owner.
+    PtePageNotOwned,
 }
 /// Hold the result of page table operations.
 pub type Result<T> = core::result::Result<T, Error>;
@@ -159,6 +167,21 @@ impl<'a, T: PagingMode, S> TableEntryMut<'a, T, S> {
     fn level(&self) -> T::Level {
         self.level
     }
+
+    /// Returns the number of 4kB pages covered by this leaf entry, which maps `vaddr`, the first
+    /// page of a `num_pages`-page range. Huge leaves must be covered entirely by the range.
+    fn pages_in_range(
+        &self,
+        vaddr: PageAddr<T::MappedAddressSpace>,
+        num_pages: u64,
+    ) -> Result<u64> {
+        let page_size = self.level.leaf_page_size();
+        let count = page_size as u64 / PageSize::Size4k as u64;
+        if page_size.is_huge() && (!vaddr.is_aligned(page_size) || count > num_pages) {
+            return Err(Error::PageSizeMismatch(PageSize::Size4k, page_size));
+        }
+        Ok(count)
+    }
 }
 
 impl<'a, T: PagingMode> UnusedPte<'a, T> {
@@ -275,6 +298,19 @@ impl<'a, T: PagingMode> LeafPte<'a, T> {
         self.pte.lock();
         LockedMappedPte::new(self.pte, self.level)
     }
+
+    /// Replaces this leaf with a pointer to the next level page table at `table_paddr`, returning
+    /// the entry as a table entry.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that `table_paddr` references a page-table page uniquely owned by
+    /// the root `GuestStagePageTable`, which must already be filled with mappings for the pages
+    /// mapped by this leaf.
+    unsafe fn replace_with_table(self, table_paddr: SupervisorPageAddr) -> PageTablePte<'a, T> {
+        self.pte.set(table_paddr.pfn(), &PteFieldBits::non_leaf());
+        PageTablePte::new(self.pte, self.level)
+    }
 }
 
 impl<'a, T: PagingMode> PageTablePte<'a, T> {
@@ -293,6 +329,26 @@ impl<'a, T: PagingMode> PageTablePte<'a, T> {
         //    `GuestStagePageTable` and have their lifetime bound to the root.
         unsafe { PageTable::from_pte(self.pte, self.level.next().unwrap()) }
     }
+
+    /// Replaces the pointer to the next level page table with a leaf mapping `paddr`, returning
+    /// the entry as a leaf.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that `paddr` references a range of pages of the leaf size for
+    /// this level uniquely owned by the root `GuestStagePageTable`, and must take care of the
+    /// page-table page this entry pointed to.
+    unsafe fn replace_with_leaf(
+        self,
+        paddr: SupervisorPageAddr,
+        perms: PteFieldBits,
+    ) -> Result<LeafPte<'a, T>> {
+        if !paddr.is_aligned(self.level.leaf_page_size()) {
+            return Err(Error::AddressMisaligned(paddr.bits()));
+        }
+        self.pte.set(paddr.pfn(), &perms);
+        Ok(LeafPte::new(self.pte, self.level))
+    }
 }
 
 /// Holds the address of a page table for a given level in the paging structure.
@@ -406,22 +462,98 @@ impl<'a, T: PagingMode> PageTable<'a, T> {
                     page_tracker.release_page(table_page).unwrap();
                 }
                 Leaf(l) => {
-                    // Unwrap ok since by virtue of being mapped into this page table, we must
-                    // uniquely own the page and it must be in a releasable state.
-                    page_tracker
-                        .release_page_by_addr(l.page_addr(), owner)
-                        .unwrap();
+                    // Ownership is tracked at 4kB granularity, so release each of the 4kB pages
+                    // making up a huge page.
+                    let num_pages = l.level().leaf_page_size() as u64 / PageSize::Size4k as u64;
+                    for addr in l.page_addr().iter_from().take(num_pages as usize) {
+                        // Unwrap ok since by virtue of being mapped into this page table, we must
+                        // uniquely own the page and it must be in a releasable state.
+                        page_tracker.release_page_by_addr(addr, owner).unwrap();
+                    }
                 }
                 Invalidated(i) => {
-                    // Unwrap ok since the only usage of invalid PTEs we currently have is for
-                    // converted pages.
-                    page_tracker
-                        .release_page_by_addr(i.page_addr(), owner)
-                        .unwrap();
+                    let num_pages = i.level().leaf_page_size() as u64 / PageSize::Size4k as u64;
+                    for addr in i.page_addr().iter_from().take(num_pages as usize) {
+                        // Unwrap ok since the only usage of invalid PTEs we currently have is for
+                        // converted pages.
+                        page_tracker.release_page_by_addr(addr, owner).unwrap();
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Unlinks every next-level table below this one that no longer holds any entries, passing the
+    /// freed table pages to `put_pte_page`. Returns whether this table is now empty along with the
+    /// number of table pages freed.
+    fn release_empty_tables(
+        &mut self,
+        put_pte_page: &mut dyn FnMut(Page<InternalDirty>),
+    ) -> (bool, u64) {
+        let mut empty = true;
+        let mut freed = 0;
+        let level = self.level;
+        for index in PageTableIndexIter::new(level) {
+            use TableEntryType::*;
+            let pte = self.entry_mut(index);
+            match TableEntryType::<T>::from_pte(&mut *pte, level) {
+                Table(t) => {
+                    let table_addr = t.table_addr();
+                    let (table_empty, table_freed) = t.table().release_empty_tables(put_pte_page);
+                    freed += table_freed;
+                    if table_empty {
+                        pte.clear();
+                        // Safe since we must uniquely own the page if we were using it as a
+                        // page-table page, and it's no longer linked into the paging hierarchy.
+                        put_pte_page(unsafe { Page::new(table_addr) });
+                        freed += 1;
+                    } else {
+                        empty = false;
+                    }
+                }
+                Unused(_) => (),
+                _ => {
+                    empty = false;
                 }
+            }
+        }
+        (empty, freed)
+    }
+
+    /// Calls `f` with the address and PTE of each valid leaf in this page table, recursing through
+    /// the paging hierarchy, until `f` breaks. `base` is the address mapped by the first entry of
+    /// this table.
+    fn for_each_leaf(
+        &mut self,
+        base: u64,
+        f: &mut dyn FnMut(u64, &Pte, T::Level) -> ControlFlow<()>,
+    ) -> ControlFlow<()> {
+        let iter = PageTableIndexIter::new(self.level);
+        for index in iter {
+            let addr = base + (index.index() << self.level.addr_shift());
+            let entry = self.entry_for_index_mut(index);
+            use TableEntryType::*;
+            match entry {
+                Table(t) => t.table().for_each_leaf(addr, f)?,
+                Leaf(l) => f(addr, l.pte, l.level)?,
+                LockedMapped(l) => f(addr, l.pte, l.level)?,
                 _ => (),
             }
         }
+        ControlFlow::Continue(())
+    }
+
+    /// Returns the number of next-level page tables below this one, recursing through the paging
+    /// hierarchy.
+    fn count_tables(&mut self) -> u64 {
+        let mut count = 0;
+        for index in PageTableIndexIter::new(self.level) {
+            if let TableEntryType::Table(t) = self.entry_for_index_mut(index) {
+                count += 1 + t.table().count_tables();
+            }
+        }
+        count
     }
 }
 
@@ -541,9 +673,9 @@ impl<T: PagingMode> PageTableInner<T> {
         if page_size.is_huge() {
             return Err(Error::UnsupportedPageSizePages(root, page_size));
         }
-        if root.base().bits() & (T::TOP_LEVEL_ALIGN - 1) != 0 {
-            return Err(Error::MisalignedPages(root));
-        }
+        let root = root
+            .with_alignment(T::TOP_LEVEL_ALIGN)
+            .map_err(Error::MisalignedPages)?;
         if root.len() < T::root_level().table_pages() as u64 {
             return Err(Error::InsufficientPages(root));
         }
@@ -639,7 +771,11 @@ impl<T: PagingMode> PageTableInner<T> {
         while table.level.leaf_page_size() != page_size {
             table = table.next_level_or_fill_fn(RawAddr::from(vaddr), get_pte_page)?;
         }
-        let entry = table.entry_for_addr_mut(RawAddr::from(vaddr));
+        Self::lock_entry_for_mapping(table.entry_for_addr_mut(RawAddr::from(vaddr)))
+    }
+
+    /// Locks the invalid leaf PTE `entry` in preparation for mapping.
+    fn lock_entry_for_mapping(entry: TableEntryType<T>) -> Result<()> {
         use TableEntryType::*;
         match entry {
             Invalidated(i) => {
@@ -656,6 +792,54 @@ impl<T: PagingMode> PageTableInner<T> {
         }
     }
 
+    /// Calls `f` with each of the 4kB leaf PTEs for the `num_pages` pages starting at `vaddr`,
+    /// filling in any missing intermediate page tables using `get_pte_page`. Unlike walking the
+    /// page table for every page, the paging hierarchy is only walked once per leaf page table.
+    ///
+    /// The caller must have checked that the range doesn't overflow the address space.
+    fn for_each_4k_leaf_entry(
+        &mut self,
+        vaddr: PageAddr<T::MappedAddressSpace>,
+        num_pages: u64,
+        get_pte_page: &mut dyn FnMut() -> Option<Page<InternalClean>>,
+        f: &mut dyn FnMut(PageAddr<T::MappedAddressSpace>, TableEntryType<T>) -> Result<()>,
+    ) -> Result<()> {
+        let mut addr = vaddr;
+        let mut remaining = num_pages;
+        while remaining > 0 {
+            let mut table = PageTable::from_root(self);
+            while !table.level.is_leaf() {
+                table = table.next_level_or_fill_fn(RawAddr::from(addr), get_pte_page)?;
+            }
+            // Fill in consecutive entries up to the end of this leaf page table.
+            let first = table.index_from_addr(RawAddr::from(addr)).index();
+            let count = core::cmp::min(remaining, (1 << table.level.addr_width()) - first);
+            for _ in 0..count {
+                f(addr, table.entry_for_addr_mut(RawAddr::from(addr)))?;
+                // Unwrap ok: the caller checked that the range doesn't overflow.
+                addr = addr.checked_add_pages(1).unwrap();
+            }
+            remaining -= count;
+        }
+        Ok(())
+    }
+
+    /// Returns the entry at the level mapping 2MB pages for `vaddr`, or `None` if the walk
+    /// terminates at a higher level.
+    fn get_2m_entry(
+        &mut self,
+        vaddr: PageAddr<T::MappedAddressSpace>,
+    ) -> Option<TableEntryType<T>> {
+        let mut table = PageTable::from_root(self);
+        while table.level.leaf_page_size() != PageSize::Size2M {
+            match table.entry_for_addr_mut(vaddr.into()) {
+                TableEntryType::Table(t) => table = t.table(),
+                _ => return None,
+            }
+        }
+        Some(table.entry_for_addr_mut(vaddr.into()))
+    }
+
     /// Locks an existing leaf PTE mapping of `vaddr` for remapping.
     fn lock_leaf_for_remapping(&mut self, vaddr: PageAddr<T::MappedAddressSpace>) -> Result<()> {
         let entry = self.walk(RawAddr::from(vaddr));
@@ -663,6 +847,10 @@ impl<T: PagingMode> PageTableInner<T> {
         match entry {
             Invalidated(_) | Unused(_) => Err(Error::PageNotMapped),
             LockedMapped(_) | LockedUnmapped(_) => Err(Error::PteLocked),
+            // Huge pages must be demoted before the 4kB pages making them up can be remapped.
+            Leaf(l) if l.level().leaf_page_size().is_huge() => {
+                Err(Error::PageSizeNotSupported(l.level().leaf_page_size()))
+            }
             Leaf(l) => {
                 l.lock();
                 Ok(())
@@ -702,25 +890,6 @@ impl<T: PagingMode> PageTableInner<T> {
             _ => Err(Error::PageNotMapped),
         }
     }
-
-    /// Returns the invalid 4kB leaf PTE mapping `vaddr` if the PFN the PTE references is a
-    /// page that was invalidated.
-    fn get_invalidated_4k_leaf(
-        &mut self,
-        vaddr: PageAddr<T::MappedAddressSpace>,
-    ) -> Result<InvalidatedPte<T>> {
-        let entry = self.walk(RawAddr::from(vaddr));
-        use TableEntryType::*;
-        match entry {
-            Invalidated(i) => {
-                if !i.level().is_leaf() {
-                    return Err(Error::PageSizeNotSupported(i.level().leaf_page_size()));
-                }
-                Ok(i)
-            }
-            _ => Err(Error::PageNotConverted),
-        }
-    }
 }
 
 /// A paging hierarchy for a given addressing type.
@@ -923,6 +1092,36 @@ impl<T: PagingMode> GuestStagePageTable<T> {
         false
     }
 
+    /// Walks the page table to determine why a guest `access` to `addr` faulted. Returns `None` if
+    /// the current translation permits the access, e.g. because the mapping was updated after the
+    /// fault was taken.
+    pub fn fault_reason(
+        &self,
+        addr: RawAddr<T::MappedAddressSpace>,
+        access: AccessType,
+    ) -> Option<FaultError> {
+        let mut inner = self.inner.lock();
+        let mut table = PageTable::from_root(&mut inner);
+        loop {
+            let level = table.level;
+            let pte = table.entry_mut(table.index_from_addr(addr));
+            if !pte.valid() {
+                return Some(FaultError::MissingLeaf);
+            }
+            if pte.leaf() {
+                return pte.check_leaf_access(access, level.leaf_page_size()).err();
+            }
+            if let Err(e) = pte.check_non_leaf() {
+                return Some(e);
+            }
+            // A non-leaf entry at the last level can't be translated any further.
+            let next_level = level.next()?;
+            // Safe since all valid, non-leaf PTEs point to an intermediate page table owned by the
+            // root `GuestStagePageTable`.
+            table = unsafe { PageTable::from_pte(pte, next_level) };
+        }
+    }
+
     /// Prepares for mapping `num_pages` pages of size `page_size` starting at `addr` in the mapped
     /// address space by locking the target PTEs and populating any intermediate page tables using
     /// `get_pte_page`. Upon success, returns a `GuestStageMapper` that is guaranteed to be able to
@@ -937,13 +1136,16 @@ impl<T: PagingMode> GuestStagePageTable<T> {
         if page_size.is_huge() {
             return Err(Error::PageSizeNotSupported(page_size));
         }
+        addr.checked_add_pages(num_pages)
+            .ok_or(Error::AddressOverflow)?;
 
         let mut mapper = GuestStageMapper::new(self, addr, 0);
         let mut inner = self.inner.lock();
-        for a in addr.iter_from().take(num_pages as usize) {
-            inner.lock_leaf_for_mapping(a, page_size, get_pte_page)?;
+        inner.for_each_4k_leaf_entry(addr, num_pages, get_pte_page, &mut |_, entry| {
+            PageTableInner::lock_entry_for_mapping(entry)?;
             mapper.num_pages += 1;
-        }
+            Ok(())
+        })?;
 
         Ok(mapper)
     }
@@ -987,36 +1189,57 @@ impl<T: PagingMode> GuestStagePageTable<T> {
             .checked_add_pages(num_pages)
             .ok_or(Error::AddressOverflow)?;
         let mut inner = self.inner.lock();
-        // TODO: Support huge pages, making sure we're not invalidating pages that are partially
-        // covered by the range.
-        for va in vaddr.iter_from().take(num_pages as usize) {
+        // Huge pages are invalidated as a whole, so they must not be partially covered by the
+        // range.
+        let mut va = vaddr;
+        let mut remaining = num_pages;
+        while remaining > 0 {
             use TableEntryType::*;
-            match inner.walk(va.into()) {
+            let count = match inner.walk(va.into()) {
                 Leaf(pte) => {
-                    if !pte.level().is_leaf() {
-                        return Err(Error::PageSizeNotSupported(pte.level().leaf_page_size()));
-                    }
-                    if !pred(pte.page_addr()) {
+                    let count = pte.pages_in_range(va, remaining)?;
+                    if !pte
+                        .page_addr()
+                        .iter_from()
+                        .take(count as usize)
+                        .all(&mut pred)
+                    {
                         return Err(Error::PredicateFailed);
                     }
+                    count
                 }
-                Unused(_) if sparse => {
-                    continue;
-                }
+                Unused(_) if sparse => 1,
                 _ => {
                     return Err(Error::PageNotMapped);
                 }
-            }
+            };
+            // Unwrap ok: we checked that the range doesn't overflow above.
+            va = va.checked_add_pages(count).unwrap();
+            remaining -= count;
         }
 
-        Ok(vaddr
-            .iter_from()
-            .take(num_pages as usize)
-            .filter_map(move |va| {
+        let mut va = vaddr;
+        let mut remaining = num_pages;
+        Ok(core::iter::from_fn(move || {
+            if remaining == 0 {
+                return None;
+            }
+            let (paddr, count) = match inner.walk(va.into()) {
+                TableEntryType::Leaf(pte) => {
+                    // Unwrap ok: we checked that the leaf is covered by the range above.
+                    let count = pte.pages_in_range(va, remaining).unwrap();
+                    (Some(pte.invalidate().page_addr()), count)
+                }
                 // Skip over unmapped PTEs -- we verified there were no invalidated or locked PTEs
                 // above.
-                Some(inner.get_mapped_4k_leaf(va).ok()?.invalidate().page_addr())
-            }))
+                _ => (None, 1),
+            };
+            va = va.checked_add_pages(count).unwrap();
+            remaining -= count;
+            Some(paddr.map(|p| (p, count)))
+        })
+        .flatten()
+        .flat_map(|(paddr, count)| paddr.iter_from().take(count as usize)))
     }
 
     /// Verifies the entire virtual address range is mapped and that `pred` returns true for
@@ -1065,39 +1288,58 @@ impl<T: PagingMode> GuestStagePageTable<T> {
             .checked_add_pages(num_pages)
             .ok_or(Error::AddressOverflow)?;
         let mut inner = self.inner.lock();
-        // TODO: Support huge pages, making sure we're not clearing PTEs that only partially cover
-        // the address range.
-        for va in vaddr.iter_from().take(num_pages as usize) {
+        // Huge pages are cleared as a whole, so they must not be partially covered by the range.
+        let mut va = vaddr;
+        let mut remaining = num_pages;
+        while remaining > 0 {
             use TableEntryType::*;
-            match inner.walk(va.into()) {
+            let count = match inner.walk(va.into()) {
                 Invalidated(pte) => {
-                    if !pte.level().is_leaf() {
-                        return Err(Error::PageSizeNotSupported(pte.level().leaf_page_size()));
-                    }
-                    if !pred(pte.page_addr()) {
+                    let count = pte.pages_in_range(va, remaining)?;
+                    if !pte
+                        .page_addr()
+                        .iter_from()
+                        .take(count as usize)
+                        .all(&mut pred)
+                    {
                         return Err(Error::PredicateFailed);
                     }
+                    count
                 }
-                Unused(_) => {
-                    continue;
-                }
+                Unused(_) => 1,
                 _ => {
                     return Err(Error::PageNotUnmappable);
                 }
-            }
+            };
+            // Unwrap ok: we checked that the range doesn't overflow above.
+            va = va.checked_add_pages(count).unwrap();
+            remaining -= count;
         }
 
-        Ok(vaddr
-            .iter_from()
-            .take(num_pages as usize)
-            .filter_map(move |va| {
+        let mut va = vaddr;
+        let mut remaining = num_pages;
+        Ok(core::iter::from_fn(move || {
+            if remaining == 0 {
+                return None;
+            }
+            let (paddr, count) = match inner.walk(va.into()) {
+                TableEntryType::Invalidated(pte) => {
+                    // Unwrap ok: we checked that the leaf is covered by the range above.
+                    let count = pte.pages_in_range(va, remaining).unwrap();
+                    let paddr = pte.page_addr();
+                    pte.clear();
+                    (Some(paddr), count)
+                }
                 // Skip over non-invalidated PTEs -- we verified there were no mapped or locked PTEs
                 // above.
-                let pte = inner.get_invalidated_4k_leaf(va).ok()?;
-                let paddr = pte.page_addr();
-                pte.clear();
-                Some(paddr)
-            }))
+                _ => (None, 1),
+            };
+            va = va.checked_add_pages(count).unwrap();
+            remaining -= count;
+            Some(paddr.map(|p| (p, count)))
+        })
+        .flatten()
+        .flat_map(|(paddr, count)| paddr.iter_from().take(count as usize)))
     }
 
     /// Verifies the entire virtual address range is mapped and that `pred` returns true for
@@ -1116,19 +1358,50 @@ impl<T: PagingMode> GuestStagePageTable<T> {
             .checked_add_pages(num_pages)
             .ok_or(Error::AddressOverflow)?;
         let mut inner = self.inner.lock();
-        // TODO: Support huge pages, making sure mappings aren't partially covering the address
-        // range.
-        for va in vaddr.iter_from().take(num_pages as usize) {
-            let paddr = inner.get_mapped_4k_leaf(va)?.page_addr();
-            if !pred(paddr) {
-                return Err(Error::PredicateFailed);
-            }
+        // Huge pages must not be partially covered by the range.
+        let mut va = vaddr;
+        let mut remaining = num_pages;
+        while remaining > 0 {
+            let count = match inner.walk(va.into()) {
+                TableEntryType::Leaf(pte) => {
+                    let count = pte.pages_in_range(va, remaining)?;
+                    if !pte
+                        .page_addr()
+                        .iter_from()
+                        .take(count as usize)
+                        .all(&mut pred)
+                    {
+                        return Err(Error::PredicateFailed);
+                    }
+                    count
+                }
+                _ => {
+                    return Err(Error::PageNotMapped);
+                }
+            };
+            // Unwrap ok: we checked that the range doesn't overflow above.
+            va = va.checked_add_pages(count).unwrap();
+            remaining -= count;
         }
 
-        Ok(vaddr.iter_from().take(num_pages as usize).map(move |va| {
+        let mut va = vaddr;
+        let mut remaining = num_pages;
+        Ok(core::iter::from_fn(move || {
+            if remaining == 0 {
+                return None;
+            }
             // Unwrap ok: We checked that the entire range was mapped above.
-            inner.get_mapped_4k_leaf(va).unwrap().page_addr()
-        }))
+            let (paddr, count) = match inner.walk(va.into()) {
+                TableEntryType::Leaf(pte) => {
+                    (pte.page_addr(), pte.pages_in_range(va, remaining).unwrap())
+                }
+                _ => unreachable!(),
+            };
+            va = va.checked_add_pages(count).unwrap();
+            remaining -= count;
+            Some(paddr.iter_from().take(count as usize))
+        })
+        .flatten())
     }
 
     /// Verifies the entire virtual address range is invalidated and that `pred` returns true for
@@ -1147,19 +1420,50 @@ impl<T: PagingMode> GuestStagePageTable<T> {
             .checked_add_pages(num_pages)
             .ok_or(Error::AddressOverflow)?;
         let mut inner = self.inner.lock();
-        // TODO: Support huge pages, making sure mappings aren't partially covering the address
-        // range.
-        for va in vaddr.iter_from().take(num_pages as usize) {
-            let paddr = inner.get_invalidated_4k_leaf(va)?.page_addr();
-            if !pred(paddr) {
-                return Err(Error::PredicateFailed);
-            }
+        // Huge pages must not be partially covered by the range.
+        let mut va = vaddr;
+        let mut remaining = num_pages;
+        while remaining > 0 {
+            let count = match inner.walk(va.into()) {
+                TableEntryType::Invalidated(pte) => {
+                    let count = pte.pages_in_range(va, remaining)?;
+                    if !pte
+                        .page_addr()
+                        .iter_from()
+                        .take(count as usize)
+                        .all(&mut pred)
+                    {
+                        return Err(Error::PredicateFailed);
+                    }
+                    count
+                }
+                _ => {
+                    return Err(Error::PageNotConverted);
+                }
+            };
+            // Unwrap ok: we checked that the range doesn't overflow above.
+            va = va.checked_add_pages(count).unwrap();
+            remaining -= count;
         }
 
-        Ok(vaddr.iter_from().take(num_pages as usize).map(move |va| {
+        let mut va = vaddr;
+        let mut remaining = num_pages;
+        Ok(core::iter::from_fn(move || {
+            if remaining == 0 {
+                return None;
+            }
             // Unwrap ok: We checked that the entire range was invalidated above.
-            inner.get_invalidated_4k_leaf(va).unwrap().page_addr()
-        }))
+            let (paddr, count) = match inner.walk(va.into()) {
+                TableEntryType::Invalidated(pte) => {
+                    (pte.page_addr(), pte.pages_in_range(va, remaining).unwrap())
+                }
+                _ => unreachable!(),
+            };
+            va = va.checked_add_pages(count).unwrap();
+            remaining -= count;
+            Some(paddr.iter_from().take(count as usize))
+        })
+        .flatten())
     }
 
     /// Returns true if the specified range is completely unpopulated, including pages that are
@@ -1171,6 +1475,307 @@ impl<T: PagingMode> GuestStagePageTable<T> {
             .take(PageSize::num_4k_pages(len) as usize)
             .all(|va| matches!(inner.walk(va.into()), TableEntryType::Unused(_)))
     }
+
+    /// Attempts to replace the 4kB mappings of the 2MB-aligned region starting at `vaddr` with a
+    /// single 2MB mapping. The region qualifies if all of its 4kB pages are mapped by unlocked
+    /// PTEs with the same permissions, are owned by the owner of this page table, and are
+    /// physically contiguous starting at a 2MB-aligned address. Returns `Ok(false)` if the region
+    /// doesn't qualify.
+    ///
+    /// The page-table page that held the 4kB mappings is passed to `put_pte_page`. Other harts may
+    /// still walk through it, so it's up to the caller to fence the address space before that page
+    /// is reused or released. Once promoted, the region can only be invalidated or unmapped as a
+    /// whole; use `demote_2m()` to operate on parts of it.
+    pub fn try_promote_2m(
+        &self,
+        vaddr: PageAddr<T::MappedAddressSpace>,
+        put_pte_page: &mut dyn FnMut(Page<InternalDirty>),
+    ) -> Result<bool> {
+        if !vaddr.is_aligned(PageSize::Size2M) {
+            return Err(Error::AddressMisaligned(vaddr.bits()));
+        }
+        let mut inner = self.inner.lock();
+        let leaf_table = match inner.get_2m_entry(vaddr) {
+            Some(TableEntryType::Table(t)) => t,
+            _ => return Ok(false),
+        };
+        let table_addr = leaf_table.table_addr();
+
+        // Check that the 4kB leaves can be merged, accumulating the accessed and dirty bits.
+        let ignored_bits = PteFieldBit::Accessed.mask() | PteFieldBit::Dirty.mask();
+        let mut table = leaf_table.table();
+        let mut base = None;
+        let mut perms = PteFieldBits::default();
+        for (i, index) in PageTableIndexIter::new(table.level).enumerate() {
+            let leaf = match table.entry_for_index_mut(index) {
+                TableEntryType::Leaf(l) => l,
+                _ => return Ok(false),
+            };
+            let paddr = leaf.page_addr();
+            let status = leaf.pte.status();
+            match base {
+                None => {
+                    if !paddr.is_aligned(PageSize::Size2M) {
+                        return Ok(false);
+                    }
+                    base = Some(paddr);
+                    perms = status;
+                }
+                Some(b) => {
+                    if Some(paddr) != b.checked_add_pages(i as u64)
+                        || status.bits() & !ignored_bits != perms.bits() & !ignored_bits
+                    {
+                        return Ok(false);
+                    }
+                    for bit in [PteFieldBit::Accessed, PteFieldBit::Dirty] {
+                        if bit.is_set(status.bits()) {
+                            perms.set_bit(bit);
+                        }
+                    }
+                }
+            }
+            if !self.page_tracker.is_owned(paddr, self.owner) {
+                return Ok(false);
+            }
+        }
+
+        // Walk again to get back at the table entry; we hold the lock so it can't have changed.
+        let table_pte = match inner.get_2m_entry(vaddr) {
+            Some(TableEntryType::Table(t)) => t,
+            _ => return Ok(false),
+        };
+        // Unwrap ok: `base` is set by the first entry of the table.
+        let base = base.unwrap();
+        unsafe {
+            // Safe since we've verified that the pages are contiguous and owned by us, and we're
+            // handing the page-table page back below.
+            table_pte.replace_with_leaf(base, perms)?;
+        }
+        // Safe since we must uniquely own the page if we were using it as a page-table page, and
+        // it's no longer linked into the paging hierarchy.
+        put_pte_page(unsafe { Page::new(table_addr) });
+        Ok(true)
+    }
+
+    /// Replaces the 2MB mapping at `vaddr` with 4kB mappings of the same physical pages and with
+    /// the same permissions, using `pt_page` to hold the new PTEs. `pt_page` must be an internal
+    /// state page of the owner of this page table, and is handed back on failure. Page ownership
+    /// is already tracked at 4kB granularity, so each of the 4kB pages remains owned by the owner
+    /// of this page table. It's up to the caller to fence the region before the new mappings are
+    /// guaranteed to be used.
+    pub fn demote_2m(
+        &self,
+        vaddr: PageAddr<T::MappedAddressSpace>,
+        pt_page: Page<InternalClean>,
+    ) -> core::result::Result<(), (Error, Page<InternalClean>)> {
+        if !vaddr.is_aligned(PageSize::Size2M) {
+            return Err((Error::AddressMisaligned(vaddr.bits()), pt_page));
+        }
+        if !self
+            .page_tracker
+            .is_internal_state_page(pt_page.addr(), self.owner)
+        {
+            return Err((Error::PtePageNotOwned, pt_page));
+        }
+        let mut inner = self.inner.lock();
+        let leaf = match inner.get_2m_entry(vaddr) {
+            Some(TableEntryType::Leaf(l)) => l,
+            Some(TableEntryType::Table(_)) => return Err((Error::TableEntryNotLeaf, pt_page)),
+            _ => return Err((Error::PageNotMapped, pt_page)),
+        };
+        let paddr = leaf.page_addr();
+        let perms = leaf.pte.status();
+
+        // Unwrap ok: the level mapping 2MB pages can't be the last level.
+        let mut table: PageTable<T> = PageTable {
+            table_addr: pt_page.addr(),
+            level: leaf.level().next().unwrap(),
+            phantom: PhantomData,
+        };
+        // Check the page up front so that we don't fail with a partially-filled table.
+        if !PageTableIndexIter::new(table.level)
+            .all(|index| matches!(table.entry_for_index_mut(index), TableEntryType::Unused(_)))
+        {
+            return Err((Error::MappingExists, pt_page));
+        }
+        // Fill in the new table before linking it in so that the region stays mapped throughout.
+        for (index, addr) in PageTableIndexIter::new(table.level).zip(paddr.iter_from()) {
+            if let TableEntryType::Unused(u) = table.entry_for_index_mut(index) {
+                // Unwrap ok: `addr` is 4kB-aligned.
+                unsafe {
+                    // Safe since `addr` is part of the 2MB page we already map.
+                    u.lock().map_leaf(addr, perms).unwrap();
+                }
+            }
+        }
+        unsafe {
+            // Safe since we uniquely own `pt_page` and it now maps the pages of the 2MB leaf.
+            leaf.replace_with_table(pt_page.addr());
+        }
+        Ok(())
+    }
+
+    /// Frees the intermediate page tables that no longer map anything, such as those left behind
+    /// after unmapping a large region, passing each freed page-table page to `put_pte_page`. The
+    /// root table is never freed. Returns the number of page-table pages freed. It's up to the
+    /// caller to fence the address space before the freed pages are reused.
+    pub fn compact(&self, put_pte_page: &mut dyn FnMut(Page<InternalDirty>)) -> u64 {
+        let mut inner = self.inner.lock();
+        let (_, freed) = PageTable::from_root(&mut inner).release_empty_tables(put_pte_page);
+        freed
+    }
+}
+
+impl<T: GuestStagePagingMode> GuestStagePageTable<T> {
+    /// Calls `f` for each valid leaf mapping in the page table, in order of increasing guest
+    /// physical address, until `f` returns `ControlFlow::Break`.
+    pub fn for_each_leaf<F>(&self, mut f: F)
+    where
+        F: FnMut(LeafInfo) -> ControlFlow<()>,
+    {
+        let owner = self.owner;
+        let mut inner = self.inner.lock();
+        let _ = PageTable::from_root(&mut inner).for_each_leaf(0, &mut |addr, pte, level| {
+            let size = level.leaf_page_size();
+            f(LeafInfo {
+                // Unwraps ok: the address mapped by a leaf is aligned to its size, as is the PFN
+                // of a valid leaf PTE.
+                gpa: PageAddr::with_alignment(RawAddr::guest(addr, owner), size).unwrap(),
+                paddr: PageAddr::from_pfn(pte.pfn(), PageSize::Size4k).unwrap(),
+                size,
+                status: pte.status(),
+            })
+        });
+    }
+
+    /// Sets the accessed bit, and for stores the dirty bit, of the leaf mapping `addr` if that leaf
+    /// otherwise permits `access`. Needed on platforms where the hardware raises a fault instead of
+    /// updating those bits itself. Returns `true` if the leaf was updated, in which case a faulting
+    /// `access` to `addr` can be retried once any stale translation for `addr` has been fenced.
+    /// The D bit of a leaf is its dirty state, as reported by `walk_one()`.
+    pub fn update_accessed_dirty(&self, addr: GuestPhysAddr, access: AccessType) -> bool {
+        let mut inner = self.inner.lock();
+        let (pte, level) = match inner.walk(addr) {
+            TableEntryType::Leaf(l) => (l.pte, l.level),
+            _ => return false,
+        };
+        pte.check_leaf_access(access, level.leaf_page_size())
+            .is_ok()
+            && pte.mark_accessed(access)
+    }
+
+    /// Walks the page table for `addr`, returning a description of the leaf that maps it, or `None`
+    /// if `addr` isn't mapped. Leaves that are locked for remapping are still in effect and are
+    /// returned as well.
+    pub fn walk_one(&self, addr: GuestPhysAddr) -> Option<LeafInfo> {
+        let mut inner = self.inner.lock();
+        let (pte, level) = match inner.walk(addr) {
+            TableEntryType::Leaf(l) => (l.pte, l.level),
+            TableEntryType::LockedMapped(l) => (l.pte, l.level),
+            _ => return None,
+        };
+        let size = level.leaf_page_size();
+        Some(LeafInfo {
+            gpa: PageAddr::with_round_down(addr, size),
+            // Unwrap ok: the PFN of a valid leaf PTE is page-aligned.
+            paddr: PageAddr::from_pfn(pte.pfn(), PageSize::Size4k).unwrap(),
+            size,
+            status: pte.status(),
+        })
+    }
+
+    /// Returns the number of 4kB pages of guest physical address space currently mapped by the page
+    /// table. Huge leaves count as the number of 4kB pages they span.
+    pub fn mapped_page_count(&self) -> u64 {
+        self.statistics().mapped_4k_pages()
+    }
+
+    /// Returns a summary of the page table: the number of leaves of each size, and the number of
+    /// page-table pages in use below the root.
+    pub fn statistics(&self) -> PageTableStatistics {
+        let mut stats = PageTableStatistics::default();
+        self.for_each_leaf(|leaf| {
+            stats.leaves[PageTableStatistics::size_index(leaf.size())] += 1;
+            ControlFlow::Continue(())
+        });
+        let mut inner = self.inner.lock();
+        stats.table_pages = PageTable::from_root(&mut inner).count_tables();
+        stats
+    }
+}
+
+/// Summary of the mappings in a `GuestStagePageTable`, as returned by
+/// `GuestStagePageTable::statistics()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PageTableStatistics {
+    leaves: [u64; 4],
+    table_pages: u64,
+}
+
+impl PageTableStatistics {
+    fn size_index(size: PageSize) -> usize {
+        match size {
+            PageSize::Size4k => 0,
+            PageSize::Size2M => 1,
+            PageSize::Size1G => 2,
+            PageSize::Size512G => 3,
+        }
+    }
+
+    /// Returns the number of leaves mapping pages of `size`.
+    pub fn leaves(&self, size: PageSize) -> u64 {
+        self.leaves[Self::size_index(size)]
+    }
+
+    /// Returns the number of 4kB pages of guest physical address space mapped by the leaves. Huge
+    /// leaves count as the number of 4kB pages they span.
+    pub fn mapped_4k_pages(&self) -> u64 {
+        [
+            PageSize::Size4k,
+            PageSize::Size2M,
+            PageSize::Size1G,
+            PageSize::Size512G,
+        ]
+        .iter()
+        .map(|&size| self.leaves(size) * PageSize::num_4k_pages(size as u64))
+        .sum()
+    }
+
+    /// Returns the number of page-table pages in use, not counting the pages of the root table.
+    pub fn table_pages(&self) -> u64 {
+        self.table_pages
+    }
+}
+
+/// Describes a single leaf mapping in a `GuestStagePageTable`, as passed to the callback of
+/// `GuestStagePageTable::for_each_leaf()`.
+pub struct LeafInfo {
+    gpa: GuestPageAddr,
+    paddr: SupervisorPageAddr,
+    size: PageSize,
+    status: PteFieldBits,
+}
+
+impl LeafInfo {
+    /// Returns the guest physical address mapped by the leaf.
+    pub fn gpa(&self) -> GuestPageAddr {
+        self.gpa
+    }
+
+    /// Returns the host physical address the leaf maps to.
+    pub fn paddr(&self) -> SupervisorPageAddr {
+        self.paddr
+    }
+
+    /// Returns the size of the page mapped by the leaf.
+    pub fn size(&self) -> PageSize {
+        self.size
+    }
+
+    /// Returns the permission and status bits of the leaf PTE.
+    pub fn status(&self) -> PteFieldBits {
+        self.status
+    }
 }
 
 impl<T: PagingMode> Drop for GuestStagePageTable<T> {
@@ -1240,6 +1845,92 @@ impl<'a, T: PagingMode> GuestStageMapper<'a, T> {
         }
     }
 
+    /// Maps the consecutive range of pages starting at `vaddr` to `pages_to_map`, consuming
+    /// `pages_to_map`. Equivalent to calling `map_page()` for each page, but only walks the paging
+    /// hierarchy once per leaf page table. Stops at the first page that fails to map.
+    pub fn map_pages<P, M, I>(
+        &self,
+        vaddr: PageAddr<T::MappedAddressSpace>,
+        pages_to_map: I,
+    ) -> Result<()>
+    where
+        P: MappablePhysPage<M>,
+        M: MeasureRequirement,
+        I: IntoIterator<Item = P>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        self.map_pages_with_progress(
+            vaddr,
+            pages_to_map,
+            NonZeroU64::new(u64::MAX).unwrap(),
+            &mut |_| true,
+        )
+        .map_err(|(e, _)| e)
+    }
+
+    /// Same as `map_pages()`, but calls `progress` with the number of pages mapped so far after
+    /// every `interval` pages. Mapping stops with `Error::Cancelled` if `progress` returns false,
+    /// leaving the pages mapped up to that point in place. Pass a mutable reference to the page
+    /// iterator to get hold of the pages that weren't mapped. If a page fails to map it is
+    /// returned along with the error.
+    pub fn map_pages_with_progress<P, M, I>(
+        &self,
+        vaddr: PageAddr<T::MappedAddressSpace>,
+        pages_to_map: I,
+        interval: NonZeroU64,
+        progress: &mut dyn FnMut(u64) -> bool,
+    ) -> core::result::Result<(), (Error, Option<P>)>
+    where
+        P: MappablePhysPage<M>,
+        M: MeasureRequirement,
+        I: IntoIterator<Item = P>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        let mut pages = pages_to_map.into_iter();
+        let num_pages = pages.len() as u64;
+        let end_vaddr = self.vaddr.checked_add_pages(self.num_pages).unwrap();
+        let map_end = vaddr
+            .checked_add_pages(num_pages)
+            .ok_or((Error::OutOfMapRange, None))?;
+        if vaddr < self.vaddr || map_end > end_vaddr {
+            return Err((Error::OutOfMapRange, None));
+        }
+
+        let mut inner = self.owner.inner.lock();
+        let pte_fields = PteFieldBits::user_leaf_with_perms(PteLeafPerms::RWX);
+        let mut mapped = 0;
+        let mut failed_page = None;
+        // All intermediate page tables must exist since the range was locked by `map_range()`.
+        inner
+            .for_each_4k_leaf_entry(vaddr, num_pages, &mut || None, &mut |_, entry| {
+                // Don't trust the iterator's reported length.
+                let page_to_map = pages.next().ok_or(Error::PagesExhausted)?;
+                use TableEntryType::*;
+                let result = match entry {
+                    _ if page_to_map.size().is_huge() => {
+                        Err(Error::PageSizeNotSupported(page_to_map.size()))
+                    }
+                    LockedUnmapped(l) => unsafe {
+                        // Safe since we uniquely own page_to_map.
+                        l.map_leaf(page_to_map.addr(), pte_fields).map(|_| ())
+                    },
+                    Unused(_) | Invalidated(_) => Err(Error::PteNotLocked),
+                    LockedMapped(_) | Leaf(_) => Err(Error::MappingExists),
+                    Table(_) => Err(Error::TableEntryNotLeaf),
+                };
+                if let Err(e) = result {
+                    failed_page = Some(page_to_map);
+                    return Err(e);
+                }
+                mapped += 1;
+                if mapped % interval.get() == 0 && !progress(mapped) {
+                    return Err(Error::Cancelled);
+                }
+                Ok(())
+            })
+            .map_err(|e| (e, failed_page.take()))
+    }
+
     /// Remaps `vaddr` to `page_to_map`, consuming `page_to_map` and returns the old SupervisorPageAddr
     /// address.
     pub fn remap_page<P: MappablePhysPage<M>, M: MeasureRequirement>(
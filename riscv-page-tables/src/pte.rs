@@ -5,7 +5,7 @@
 // Allow unused code until all features are added to the owning crate.
 #![allow(dead_code)]
 
-use riscv_pages::{Pfn, SupervisorPfn};
+use riscv_pages::{PageSize, Pfn, SupervisorPfn};
 
 // Both Sv39 and Sv48 use 44 bits for the page frame number.
 const PFN_BITS: u64 = 44;
@@ -82,6 +82,38 @@ const MASK_RWX: u64 = (1 << PteFieldBit::Read.shift())
     | (1 << PteFieldBit::Write.shift())
     | (1 << PteFieldBit::Execute.shift());
 
+// Bits 54-63 are reserved for the Svnapot and Svpbmt extensions or for future use, none of which
+// we support.
+const MASK_RESERVED: u64 = !((1 << 54) - 1);
+
+// The D, A and U bits are reserved in non-leaf PTEs.
+const MASK_NON_LEAF_RESERVED: u64 =
+    PteFieldBit::Dirty.mask() | PteFieldBit::Accessed.mask() | PteFieldBit::User.mask();
+
+/// The type of a guest memory access that resulted in a fault.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessType {
+    /// A load from memory.
+    Load,
+    /// A store or AMO to memory.
+    Store,
+    /// An instruction fetch.
+    Fetch,
+}
+
+/// The reason a guest-stage translation faulted, as found by walking the page table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultError {
+    /// There's no valid leaf PTE mapping the faulting address.
+    MissingLeaf,
+    /// The leaf PTE doesn't permit the access.
+    PermissionViolation,
+    /// A PTE along the walk has reserved bits set or uses a reserved permission encoding.
+    ReservedBitSet,
+    /// A superpage leaf PTE points to an address that isn't aligned to the superpage size.
+    MisalignedSuperpage,
+}
+
 /// Represents a PTE in memory. Never instantiated. Only used as a reference to entries in a page
 /// table.
 pub(crate) struct Pte(u64);
@@ -152,6 +184,18 @@ impl Pte {
         self.0 = 0;
     }
 
+    /// Sets the accessed bit, and also the dirty bit if `access` is a store. Returns `true` if any
+    /// of those bits was previously clear.
+    pub fn mark_accessed(&mut self, access: AccessType) -> bool {
+        let mut mask = PteFieldBit::Accessed.mask();
+        if access == AccessType::Store {
+            mask |= PteFieldBit::Dirty.mask();
+        }
+        let updated = self.0 & mask != mask;
+        self.0 |= mask;
+        updated
+    }
+
     /// Returns `true` if the entry is a leaf.
     pub fn leaf(&self) -> bool {
         self.bits() & MASK_RWX != 0
@@ -161,6 +205,49 @@ impl Pte {
     pub fn pfn(&self) -> SupervisorPfn {
         Pfn::supervisor((self.bits() >> PFN_SHIFT) & PFN_MASK)
     }
+
+    /// Returns the status bits of this entry.
+    pub fn status(&self) -> PteFieldBits {
+        PteFieldBits {
+            bits: self.bits() & ((1 << PFN_SHIFT) - 1),
+        }
+    }
+
+    /// Checks the reserved bits of a valid, non-leaf entry.
+    pub fn check_non_leaf(&self) -> Result<(), FaultError> {
+        if self.bits() & (MASK_RESERVED | MASK_NON_LEAF_RESERVED) != 0 {
+            return Err(FaultError::ReservedBitSet);
+        }
+        Ok(())
+    }
+
+    /// Checks if a guest-stage `access` through this entry, a valid leaf mapping a page of
+    /// `page_size`, would succeed, returning the reason it would fault otherwise.
+    pub fn check_leaf_access(
+        &self,
+        access: AccessType,
+        page_size: PageSize,
+    ) -> Result<(), FaultError> {
+        let bits = self.bits();
+        let is_set = |bit: PteFieldBit| bit.is_set(bits);
+        if bits & MASK_RESERVED != 0 || (is_set(PteFieldBit::Write) && !is_set(PteFieldBit::Read)) {
+            return Err(FaultError::ReservedBitSet);
+        }
+        let pages_per_leaf = page_size as u64 / PageSize::Size4k as u64;
+        if self.pfn().bits() % pages_per_leaf != 0 {
+            return Err(FaultError::MisalignedSuperpage);
+        }
+        let perm = match access {
+            AccessType::Load => PteFieldBit::Read,
+            AccessType::Store => PteFieldBit::Write,
+            AccessType::Fetch => PteFieldBit::Execute,
+        };
+        // Guest-stage accesses are always treated as U-mode accesses.
+        if !is_set(PteFieldBit::User) || !is_set(perm) {
+            return Err(FaultError::PermissionViolation);
+        }
+        Ok(())
+    }
 }
 
 /// The status bits that define PTE state.
@@ -206,3 +293,116 @@ impl PteFieldBits {
         Self::default()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(pfn: u64, perms: PteLeafPerms) -> Pte {
+        let mut pte = Pte(0);
+        unsafe {
+            // Safe since the PTE isn't part of any page table.
+            pte.set(
+                Pfn::supervisor(pfn),
+                &PteFieldBits::user_leaf_with_perms(perms),
+            )
+        };
+        pte
+    }
+
+    #[test]
+    fn leaf_access_permitted() {
+        let pte = leaf(0x1000, PteLeafPerms::URW);
+        assert!(pte
+            .check_leaf_access(AccessType::Load, PageSize::Size4k)
+            .is_ok());
+        assert!(pte
+            .check_leaf_access(AccessType::Store, PageSize::Size4k)
+            .is_ok());
+        // PFN is 2M-aligned.
+        let pte = leaf(0x1_0000, PteLeafPerms::URX);
+        assert!(pte
+            .check_leaf_access(AccessType::Fetch, PageSize::Size2M)
+            .is_ok());
+    }
+
+    #[test]
+    fn leaf_permission_violation() {
+        let pte = leaf(0x1000, PteLeafPerms::UR);
+        assert_eq!(
+            pte.check_leaf_access(AccessType::Store, PageSize::Size4k),
+            Err(FaultError::PermissionViolation)
+        );
+        assert_eq!(
+            pte.check_leaf_access(AccessType::Fetch, PageSize::Size4k),
+            Err(FaultError::PermissionViolation)
+        );
+        // Guest-stage mappings must be accessible from U-mode.
+        let mut pte = Pte(0);
+        unsafe {
+            // Safe since the PTE isn't part of any page table.
+            pte.set(
+                Pfn::supervisor(0x1000),
+                &PteFieldBits::leaf_with_perms(PteLeafPerms::RWX),
+            )
+        };
+        assert_eq!(
+            pte.check_leaf_access(AccessType::Load, PageSize::Size4k),
+            Err(FaultError::PermissionViolation)
+        );
+    }
+
+    #[test]
+    fn leaf_reserved_bits() {
+        let mut pte = leaf(0x1000, PteLeafPerms::URW);
+        pte.0 |= 1 << 60;
+        assert_eq!(
+            pte.check_leaf_access(AccessType::Load, PageSize::Size4k),
+            Err(FaultError::ReservedBitSet)
+        );
+        // Write without read is a reserved encoding.
+        let mut pte = leaf(0x1000, PteLeafPerms::URW);
+        pte.0 &= !PteFieldBit::Read.mask();
+        assert_eq!(
+            pte.check_leaf_access(AccessType::Store, PageSize::Size4k),
+            Err(FaultError::ReservedBitSet)
+        );
+    }
+
+    #[test]
+    fn leaf_misaligned_superpage() {
+        // A 2M page must have the low 9 bits of the PFN clear.
+        let pte = leaf(0x1001, PteLeafPerms::URW);
+        assert!(pte
+            .check_leaf_access(AccessType::Load, PageSize::Size4k)
+            .is_ok());
+        assert_eq!(
+            pte.check_leaf_access(AccessType::Load, PageSize::Size2M),
+            Err(FaultError::MisalignedSuperpage)
+        );
+    }
+
+    #[test]
+    fn non_leaf_reserved_bits() {
+        let mut pte = Pte(0);
+        unsafe {
+            // Safe since the PTE isn't part of any page table.
+            pte.set(Pfn::supervisor(0x1000), &PteFieldBits::non_leaf())
+        };
+        assert!(pte.check_non_leaf().is_ok());
+        pte.0 |= PteFieldBit::Accessed.mask();
+        assert_eq!(pte.check_non_leaf(), Err(FaultError::ReservedBitSet));
+    }
+
+    #[test]
+    fn leaf_mark_accessed() {
+        let mut pte = leaf(0x1000, PteLeafPerms::RW);
+        assert!(pte.mark_accessed(AccessType::Load));
+        assert!(PteFieldBit::Accessed.is_set(pte.bits()));
+        assert!(!PteFieldBit::Dirty.is_set(pte.bits()));
+        assert!(!pte.mark_accessed(AccessType::Load));
+        assert!(pte.mark_accessed(AccessType::Store));
+        assert!(PteFieldBit::Dirty.is_set(pte.bits()));
+        assert!(!pte.mark_accessed(AccessType::Store));
+    }
+}
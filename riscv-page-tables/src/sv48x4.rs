@@ -101,13 +101,86 @@ impl PagingMode for Sv48x4 {
 mod tests {
     use crate::test_stubs::*;
     use alloc::vec::Vec;
+    use core::ops::ControlFlow;
     use page_tracking::*;
     use riscv_pages::*;
     use std::{mem, slice};
 
     use crate::page_table::*;
+    use crate::pte::{AccessType, FaultError, PteFieldBit};
     use crate::sv48x4::Sv48x4;
 
+    struct StubGuestPageTable {
+        guest_page_table: GuestStagePageTable<Sv48x4>,
+        page_tracker: PageTracker,
+        host_pages: PageList<Page<ConvertedClean>>,
+        pte_pages: SeqPageIter<InternalClean>,
+        gpa_base: GuestPageAddr,
+    }
+
+    // Creates an empty page table owned by the host from the stub system memory, along with the
+    // remaining host and page-table pages, and the address at which the tests map pages.
+    fn stub_guest_page_table() -> StubGuestPageTable {
+        let state = stub_sys_memory();
+        let guest_page_table = GuestStagePageTable::new(
+            state.root_pages,
+            PageOwnerId::host(),
+            state.page_tracker.clone(),
+        )
+        .expect("creating sv48x4");
+        StubGuestPageTable {
+            guest_page_table,
+            page_tracker: state.page_tracker,
+            host_pages: state.host_pages,
+            pte_pages: state.pte_pages.into_iter(),
+            gpa_base: PageAddr::new(RawAddr::guest(0x8000_0000, PageOwnerId::host())).unwrap(),
+        }
+    }
+
+    const PAGES_PER_2M: u64 = 512;
+
+    // Like `stub_guest_page_table()`, but with `num_2m` 2MB regions of 4kB pages mapped at
+    // `gpa_base`. The pages are physically contiguous starting at the 2MB-aligned host address
+    // that is returned, unless `rearrange` reorders them before they're mapped.
+    fn stub_mapped_2m(
+        num_2m: u64,
+        rearrange: impl FnOnce(&mut [Page<ConvertedClean>]),
+    ) -> (StubGuestPageTable, SupervisorPageAddr) {
+        let mut stub = stub_guest_page_table();
+        let num_pages = num_2m * PAGES_PER_2M;
+        // Skip ahead to a 2MB-aligned, physically contiguous run of pages.
+        let mut pages: Vec<Page<ConvertedClean>> = stub
+            .host_pages
+            .by_ref()
+            .skip_while(|p| !p.addr().is_aligned(PageSize::Size2M))
+            .take(num_pages as usize)
+            .collect();
+        let hpa_base = pages[0].addr();
+        for (page, addr) in pages.iter().zip(hpa_base.iter_from()) {
+            assert_eq!(page.addr(), addr);
+        }
+        rearrange(&mut pages);
+
+        let pte_pages = &mut stub.pte_pages;
+        let mapper = stub
+            .guest_page_table
+            .map_range(stub.gpa_base, PageSize::Size4k, num_pages, &mut || {
+                pte_pages.next()
+            })
+            .unwrap();
+        let mappable: Vec<_> = pages
+            .into_iter()
+            .map(|p| {
+                stub.page_tracker
+                    .assign_page_for_mapping(p, PageOwnerId::host())
+                    .unwrap()
+            })
+            .collect();
+        mapper.map_pages(stub.gpa_base, mappable).unwrap();
+        drop(mapper);
+        (stub, hpa_base)
+    }
+
     #[test]
     fn ownership_root_pages() {
         let state = stub_sys_memory();
@@ -123,19 +196,17 @@ mod tests {
 
     #[test]
     fn map_and_unmap_sv48x4() {
-        let state = stub_sys_memory();
-
-        let page_tracker = state.page_tracker;
-        let mut host_pages = state.host_pages;
+        let StubGuestPageTable {
+            guest_page_table,
+            page_tracker,
+            mut host_pages,
+            mut pte_pages,
+            gpa_base,
+        } = stub_guest_page_table();
         let id = PageOwnerId::host();
-        let guest_page_table: GuestStagePageTable<Sv48x4> =
-            GuestStagePageTable::new(state.root_pages, id, page_tracker.clone())
-                .expect("creating sv48x4");
 
         let pages_to_map = [host_pages.next().unwrap(), host_pages.next().unwrap()];
         let page_addrs: Vec<SupervisorPageAddr> = pages_to_map.iter().map(|p| p.addr()).collect();
-        let mut pte_pages = state.pte_pages.into_iter();
-        let gpa_base = PageAddr::new(RawAddr::guest(0x8000_0000, PageOwnerId::host())).unwrap();
         let mapper = guest_page_table
             .map_range(gpa_base, PageSize::Size4k, 2, &mut || pte_pages.next())
             .unwrap();
@@ -185,4 +256,573 @@ mod tests {
         assert_eq!(clean_page.get_u64(0).unwrap(), 0);
         page_tracker.unlock_page(clean_page).unwrap();
     }
+
+    #[test]
+    fn map_pages_sv48x4() {
+        let StubGuestPageTable {
+            guest_page_table,
+            page_tracker,
+            mut host_pages,
+            mut pte_pages,
+            gpa_base,
+        } = stub_guest_page_table();
+        let id = PageOwnerId::host();
+
+        // Map enough pages to span more than one leaf page table.
+        const NUM_PAGES: u64 = 1024;
+        let pages_to_map: Vec<Page<ConvertedClean>> =
+            (0..NUM_PAGES).map(|_| host_pages.next().unwrap()).collect();
+        let page_addrs: Vec<SupervisorPageAddr> = pages_to_map.iter().map(|p| p.addr()).collect();
+        let mapper = guest_page_table
+            .map_range(gpa_base, PageSize::Size4k, NUM_PAGES, &mut || {
+                pte_pages.next()
+            })
+            .unwrap();
+        let mappable: Vec<_> = pages_to_map
+            .into_iter()
+            .map(|p| page_tracker.assign_page_for_mapping(p, id).unwrap())
+            .collect();
+        mapper.map_pages(gpa_base, mappable).unwrap();
+        drop(mapper);
+
+        // The translations must match those found by walking the page table for each page.
+        let mapped: Vec<SupervisorPageAddr> = guest_page_table
+            .get_mapped_pages(gpa_base, NUM_PAGES * PageSize::Size4k as u64, |addr| {
+                page_tracker.is_mapped_page(addr, id, MemType::Ram)
+            })
+            .unwrap()
+            .collect();
+        assert_eq!(mapped, page_addrs);
+    }
+
+    #[test]
+    fn fault_reason_sv48x4() {
+        let StubGuestPageTable {
+            guest_page_table,
+            page_tracker,
+            mut host_pages,
+            mut pte_pages,
+            gpa_base,
+        } = stub_guest_page_table();
+        let id = PageOwnerId::host();
+
+        let mapper = guest_page_table
+            .map_range(gpa_base, PageSize::Size4k, 1, &mut || pte_pages.next())
+            .unwrap();
+        let mappable = page_tracker
+            .assign_page_for_mapping(host_pages.next().unwrap(), id)
+            .unwrap();
+        mapper.map_page(gpa_base, mappable).unwrap();
+        drop(mapper);
+
+        // Accesses to the mapped page are permitted.
+        for access in [AccessType::Load, AccessType::Store, AccessType::Fetch] {
+            assert_eq!(guest_page_table.fault_reason(gpa_base.into(), access), None);
+        }
+        // Neighbouring pages, with or without intermediate tables, are not mapped.
+        let next_page = gpa_base.checked_add_pages(1).unwrap();
+        assert_eq!(
+            guest_page_table.fault_reason(next_page.into(), AccessType::Load),
+            Some(FaultError::MissingLeaf)
+        );
+        let far_page = PageAddr::new(RawAddr::guest(0x1_0000_0000, PageOwnerId::host())).unwrap();
+        assert_eq!(
+            guest_page_table.fault_reason(far_page.into(), AccessType::Store),
+            Some(FaultError::MissingLeaf)
+        );
+
+        // The A and D bits are set by the first access that needs them.
+        let status = || {
+            guest_page_table
+                .walk_one(gpa_base.into())
+                .unwrap()
+                .status()
+                .bits()
+        };
+        let update = |addr: GuestPageAddr, access| {
+            guest_page_table.update_accessed_dirty(addr.into(), access)
+        };
+        assert!(!PteFieldBit::Accessed.is_set(status()));
+        assert!(update(gpa_base, AccessType::Load));
+        assert!(PteFieldBit::Accessed.is_set(status()));
+        assert!(!PteFieldBit::Dirty.is_set(status()));
+        assert!(!update(gpa_base, AccessType::Fetch));
+        assert!(update(gpa_base, AccessType::Store));
+        assert!(PteFieldBit::Dirty.is_set(status()));
+        assert!(!update(gpa_base, AccessType::Store));
+        assert!(!update(next_page, AccessType::Load));
+    }
+
+    #[test]
+    fn resolve_clean_store_sv48x4() {
+        let StubGuestPageTable {
+            guest_page_table,
+            page_tracker,
+            mut host_pages,
+            mut pte_pages,
+            gpa_base,
+        } = stub_guest_page_table();
+        let id = PageOwnerId::host();
+
+        let mapper = guest_page_table
+            .map_range(gpa_base, PageSize::Size4k, 2, &mut || pte_pages.next())
+            .unwrap();
+        for gpa in gpa_base.iter_from().take(2) {
+            let mappable = page_tracker
+                .assign_page_for_mapping(host_pages.next().unwrap(), id)
+                .unwrap();
+            mapper.map_page(gpa, mappable).unwrap();
+        }
+        drop(mapper);
+
+        // Mock of a store by hardware that doesn't update A/D bits itself: it faults unless the
+        // leaf is already accessed and dirty, and the fault is handled by updating the bits.
+        let dirty = |addr| {
+            let status = guest_page_table.walk_one(addr).unwrap().status().bits();
+            PteFieldBit::Accessed.is_set(status) && PteFieldBit::Dirty.is_set(status)
+        };
+        let mut faults = 0;
+        let mut store = |addr| {
+            while !dirty(addr) {
+                faults += 1;
+                assert!(faults <= 1, "store fault wasn't resolved");
+                assert!(guest_page_table.update_accessed_dirty(addr, AccessType::Store));
+            }
+        };
+
+        // A store to a page that was never accessed faults once, which marks the page dirty, and
+        // then goes through. Nothing faults on the now dirty page.
+        let second_page = gpa_base.checked_add_pages(1).unwrap();
+        let addr = RawAddr::guest(second_page.bits() + 0x80, PageOwnerId::host());
+        store(addr);
+        store(addr);
+        assert_eq!(faults, 1);
+        assert!(dirty(addr));
+        assert!(!guest_page_table.update_accessed_dirty(addr, AccessType::Store));
+
+        // The untouched neighbour is still clean.
+        let status = guest_page_table
+            .walk_one(gpa_base.into())
+            .unwrap()
+            .status()
+            .bits();
+        assert!(!PteFieldBit::Accessed.is_set(status));
+        assert!(!PteFieldBit::Dirty.is_set(status));
+    }
+
+    #[test]
+    fn map_pages_with_progress_sv48x4() {
+        let StubGuestPageTable {
+            guest_page_table,
+            page_tracker,
+            mut host_pages,
+            mut pte_pages,
+            gpa_base,
+        } = stub_guest_page_table();
+        let id = PageOwnerId::host();
+
+        const NUM_PAGES: u64 = 1000;
+        let mapper = guest_page_table
+            .map_range(gpa_base, PageSize::Size4k, NUM_PAGES, &mut || {
+                pte_pages.next()
+            })
+            .unwrap();
+        let mappable: Vec<_> = (0..NUM_PAGES)
+            .map(|_| {
+                page_tracker
+                    .assign_page_for_mapping(host_pages.next().unwrap(), id)
+                    .unwrap()
+            })
+            .collect();
+
+        // Map the first half, reporting progress every 64 pages.
+        let mut pages = mappable.into_iter();
+        let mut reported = Vec::new();
+        assert!(mapper
+            .map_pages_with_progress(
+                gpa_base,
+                (&mut pages).take(500),
+                core::num::NonZeroU64::new(64).unwrap(),
+                &mut |count| {
+                    reported.push(count);
+                    true
+                },
+            )
+            .is_ok());
+        assert_eq!(reported, (1..=7).map(|i| i * 64).collect::<Vec<u64>>());
+
+        // Cancel mapping the second half after the first progress report.
+        let second_half = gpa_base.checked_add_pages(500).unwrap();
+        let mut calls = 0;
+        assert!(matches!(
+            mapper.map_pages_with_progress(
+                second_half,
+                &mut pages,
+                core::num::NonZeroU64::new(100).unwrap(),
+                &mut |_| {
+                    calls += 1;
+                    false
+                },
+            ),
+            Err((Error::Cancelled, None))
+        ));
+        assert_eq!(calls, 1);
+        assert_eq!(pages.len(), 400);
+
+        // A page that fails to map is handed back.
+        let page = pages.next().unwrap();
+        let page_addr = page.addr();
+        match mapper.map_pages_with_progress(
+            gpa_base,
+            [page],
+            core::num::NonZeroU64::new(1).unwrap(),
+            &mut |_| true,
+        ) {
+            Err((Error::MappingExists, Some(p))) => assert_eq!(p.addr(), page_addr),
+            _ => panic!("Expected MappingExists"),
+        }
+
+        // An iterator that runs short of its advertised length is caught.
+        let start = gpa_base.checked_add_pages(600).unwrap();
+        let short = ShortIter {
+            pages: &mut pages,
+            len: 10,
+        };
+        assert!(matches!(
+            mapper.map_pages_with_progress(
+                start,
+                short,
+                core::num::NonZeroU64::new(1).unwrap(),
+                &mut |_| true
+            ),
+            Err((Error::PagesExhausted, None))
+        ));
+        drop(mapper);
+
+        // Only the pages mapped before cancellation, and those the short iterator did yield, are
+        // present.
+        assert!(guest_page_table
+            .get_mapped_pages(gpa_base, 605 * PageSize::Size4k as u64, |_| true)
+            .is_ok());
+        assert!(guest_page_table.range_is_empty(
+            gpa_base.checked_add_pages(605).unwrap(),
+            395 * PageSize::Size4k as u64
+        ));
+    }
+
+    // Claims to hold `len` pages, but yields only half of them.
+    struct ShortIter<'a, I: Iterator> {
+        pages: &'a mut I,
+        len: usize,
+    }
+
+    impl<'a, I: Iterator> Iterator for ShortIter<'a, I> {
+        type Item = I::Item;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            if self.len <= 5 {
+                return None;
+            }
+            self.len -= 1;
+            self.pages.next()
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            (self.len, Some(self.len))
+        }
+    }
+
+    impl<'a, I: Iterator> ExactSizeIterator for ShortIter<'a, I> {}
+
+    #[test]
+    fn for_each_leaf_sv48x4() {
+        let StubGuestPageTable {
+            guest_page_table,
+            page_tracker,
+            mut host_pages,
+            mut pte_pages,
+            gpa_base,
+        } = stub_guest_page_table();
+        let id = PageOwnerId::host();
+
+        let mapper = guest_page_table
+            .map_range(gpa_base, PageSize::Size4k, 4, &mut || pte_pages.next())
+            .unwrap();
+        let mut page_addrs = Vec::new();
+        for gpa in gpa_base.iter_from().take(4) {
+            let page = host_pages.next().unwrap();
+            page_addrs.push(page.addr());
+            let mappable = page_tracker.assign_page_for_mapping(page, id).unwrap();
+            mapper.map_page(gpa, mappable).unwrap();
+        }
+        drop(mapper);
+
+        assert_eq!(guest_page_table.mapped_page_count(), 4);
+
+        // A single-address walk finds the leaf mapping the page containing the address.
+        let addr = RawAddr::guest(gpa_base.bits() + 0x1234, PageOwnerId::host());
+        let leaf = guest_page_table.walk_one(addr).unwrap();
+        assert_eq!(leaf.gpa(), gpa_base.checked_add_pages(1).unwrap());
+        assert_eq!(leaf.paddr(), page_addrs[1]);
+        assert_eq!(leaf.size(), PageSize::Size4k);
+        assert!(leaf.status().bits() & PteFieldBit::Write.mask() != 0);
+        let unmapped = gpa_base.checked_add_pages(4).unwrap();
+        assert!(guest_page_table.walk_one(unmapped.into()).is_none());
+
+        // Every leaf is visited in order.
+        let mut leaves = Vec::new();
+        guest_page_table.for_each_leaf(|leaf| {
+            assert_eq!(leaf.size(), PageSize::Size4k);
+            leaves.push((leaf.gpa(), leaf.paddr()));
+            ControlFlow::Continue(())
+        });
+        let expected: Vec<_> = gpa_base.iter_from().zip(page_addrs).collect();
+        assert_eq!(leaves, expected);
+
+        // Breaking out after the first leaf stops the walk.
+        let mut visited = 0;
+        guest_page_table.for_each_leaf(|leaf| {
+            assert_eq!(leaf.gpa(), gpa_base);
+            visited += 1;
+            ControlFlow::Break(())
+        });
+        assert_eq!(visited, 1);
+    }
+
+    #[test]
+    fn promote_2m_sv48x4() {
+        // Swap two pages in the second region so that it isn't physically contiguous.
+        let (
+            StubGuestPageTable {
+                guest_page_table,
+                page_tracker,
+                gpa_base,
+                ..
+            },
+            hpa_base,
+        ) = stub_mapped_2m(2, |pages| {
+            pages.swap(PAGES_PER_2M as usize + 1, PAGES_PER_2M as usize + 2)
+        });
+        // One table at each of the three levels below the root, plus a second leaf table.
+        let stats = guest_page_table.statistics();
+        assert_eq!(stats.leaves(PageSize::Size4k), 2 * PAGES_PER_2M);
+        assert_eq!(stats.leaves(PageSize::Size2M), 0);
+        assert_eq!(stats.table_pages(), 4);
+
+        // Only the contiguous region gets promoted.
+        let second_region = gpa_base.checked_add_pages(PAGES_PER_2M).unwrap();
+        let mut freed = Vec::new();
+        assert!(guest_page_table
+            .try_promote_2m(gpa_base, &mut |p| freed.push(p))
+            .unwrap());
+        assert!(!guest_page_table
+            .try_promote_2m(second_region, &mut |_| panic!("nothing to free"))
+            .unwrap());
+        // The old leaf table is handed back, still owned, for the caller to release once fenced.
+        assert_eq!(freed.len(), 1);
+        assert!(page_tracker.is_internal_state_page(freed[0].addr(), PageOwnerId::host()));
+
+        let mut leaves = Vec::new();
+        guest_page_table.for_each_leaf(|leaf| {
+            leaves.push((leaf.gpa(), leaf.paddr(), leaf.size()));
+            ControlFlow::Continue(())
+        });
+        assert_eq!(leaves.len(), 1 + PAGES_PER_2M as usize);
+        assert_eq!(leaves[0], (gpa_base, hpa_base, PageSize::Size2M));
+        assert_eq!(leaves[1].0, second_region);
+        assert_eq!(leaves[1].2, PageSize::Size4k);
+        // The 2MB leaf still accounts for all of the 4kB pages it replaced.
+        assert_eq!(guest_page_table.mapped_page_count(), 2 * PAGES_PER_2M);
+        // The leaf table of the promoted region is no longer in use.
+        let stats = guest_page_table.statistics();
+        assert_eq!(stats.leaves(PageSize::Size4k), PAGES_PER_2M);
+        assert_eq!(stats.leaves(PageSize::Size2M), 1);
+        assert_eq!(stats.leaves(PageSize::Size1G), 0);
+        assert_eq!(stats.table_pages(), 3);
+        let addr = RawAddr::guest(gpa_base.bits() + 0x12_3456, PageOwnerId::host());
+        let leaf = guest_page_table.walk_one(addr).unwrap();
+        assert_eq!(leaf.gpa(), gpa_base);
+        assert_eq!(leaf.paddr(), hpa_base);
+        assert_eq!(leaf.size(), PageSize::Size2M);
+
+        // Misaligned addresses are rejected outright.
+        assert!(guest_page_table
+            .try_promote_2m(gpa_base.checked_add_pages(1).unwrap(), &mut |_| {
+                panic!("nothing to free")
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn unmap_promoted_2m_sv48x4() {
+        let (
+            StubGuestPageTable {
+                guest_page_table,
+                gpa_base,
+                ..
+            },
+            hpa_base,
+        ) = stub_mapped_2m(1, |_| ());
+        let expected: Vec<SupervisorPageAddr> =
+            hpa_base.iter_from().take(PAGES_PER_2M as usize).collect();
+        assert!(guest_page_table
+            .try_promote_2m(gpa_base, &mut |_| ())
+            .unwrap());
+        let len = PAGES_PER_2M * PageSize::Size4k as u64;
+
+        // The 2MB mapping can't be operated on piecemeal.
+        let second_page = gpa_base.checked_add_pages(1).unwrap();
+        assert!(matches!(
+            guest_page_table.invalidate_range(gpa_base, PageSize::Size4k as u64, |_| true),
+            Err(Error::PageSizeMismatch(PageSize::Size4k, PageSize::Size2M))
+        ));
+        assert!(matches!(
+            guest_page_table.get_mapped_pages(second_page, PageSize::Size4k as u64, |_| true),
+            Err(Error::PageSizeMismatch(PageSize::Size4k, PageSize::Size2M))
+        ));
+        assert!(guest_page_table
+            .remap_range(gpa_base, PageSize::Size4k, 1)
+            .is_err());
+
+        // Covering all of it yields each of the 4kB pages making it up.
+        let mapped: Vec<SupervisorPageAddr> = guest_page_table
+            .get_mapped_pages(gpa_base, len, |_| true)
+            .unwrap()
+            .collect();
+        assert_eq!(mapped, expected);
+        let mut checked = 0;
+        assert!(matches!(
+            guest_page_table.invalidate_range(gpa_base, len, |_| {
+                checked += 1;
+                checked < PAGES_PER_2M
+            }),
+            Err(Error::PredicateFailed)
+        ));
+        let invalidated: Vec<SupervisorPageAddr> = guest_page_table
+            .invalidate_range(gpa_base, len, |_| true)
+            .unwrap()
+            .collect();
+        assert_eq!(invalidated, expected);
+        let invalidated: Vec<SupervisorPageAddr> = guest_page_table
+            .get_invalidated_pages(gpa_base, len, |_| true)
+            .unwrap()
+            .collect();
+        assert_eq!(invalidated, expected);
+        let unmapped: Vec<SupervisorPageAddr> = guest_page_table
+            .unmap_range(gpa_base, len, |_| true)
+            .unwrap()
+            .collect();
+        assert_eq!(unmapped, expected);
+        assert!(guest_page_table.range_is_empty(gpa_base, len));
+    }
+
+    #[test]
+    fn demote_2m_sv48x4() {
+        let (
+            StubGuestPageTable {
+                guest_page_table,
+                page_tracker,
+                mut host_pages,
+                mut pte_pages,
+                gpa_base,
+            },
+            hpa_base,
+        ) = stub_mapped_2m(1, |_| ());
+        let id = PageOwnerId::host();
+        let mut perms = None;
+        guest_page_table.for_each_leaf(|leaf| {
+            perms = Some(leaf.status().bits());
+            ControlFlow::Break(())
+        });
+        assert!(guest_page_table
+            .try_promote_2m(gpa_base, &mut |_| ())
+            .unwrap());
+
+        // Can't demote what isn't a 2MB mapping. The PTE page is handed back.
+        let pt_page = pte_pages.next().unwrap();
+        let pt_addr = pt_page.addr();
+        let unmapped = gpa_base.checked_add_pages(PAGES_PER_2M).unwrap();
+        let pt_page = match guest_page_table.demote_2m(unmapped, pt_page) {
+            Err((Error::PageNotMapped, p)) => p,
+            _ => panic!("Expected PageNotMapped"),
+        };
+        assert_eq!(pt_page.addr(), pt_addr);
+
+        // The PTE page must be one of our internal state pages.
+        // Safe since the page isn't used otherwise, and we don't touch its contents.
+        let not_pte_page: Page<InternalClean> =
+            unsafe { Page::new(host_pages.next().unwrap().addr()) };
+        let not_pte_addr = not_pte_page.addr();
+        match guest_page_table.demote_2m(gpa_base, not_pte_page) {
+            Err((Error::PtePageNotOwned, p)) => assert_eq!(p.addr(), not_pte_addr),
+            _ => panic!("Expected PtePageNotOwned"),
+        }
+
+        assert!(guest_page_table.demote_2m(gpa_base, pt_page).is_ok());
+        let mut leaves = Vec::new();
+        guest_page_table.for_each_leaf(|leaf| {
+            assert_eq!(leaf.size(), PageSize::Size4k);
+            assert_eq!(Some(leaf.status().bits()), perms);
+            leaves.push((leaf.gpa(), leaf.paddr()));
+            ControlFlow::Continue(())
+        });
+        let expected: Vec<_> = gpa_base
+            .iter_from()
+            .zip(hpa_base.iter_from())
+            .take(PAGES_PER_2M as usize)
+            .collect();
+        assert_eq!(leaves, expected);
+
+        // Each 4kB page can now be handled on its own.
+        let one_page = gpa_base.checked_add_pages(7).unwrap();
+        let mapped: Vec<_> = guest_page_table
+            .get_mapped_pages(one_page, PageSize::Size4k as u64, |addr| {
+                page_tracker.is_mapped_page(addr, id, MemType::Ram)
+            })
+            .unwrap()
+            .collect();
+        assert_eq!(mapped, vec![hpa_base.checked_add_pages(7).unwrap()]);
+    }
+
+    #[test]
+    fn compact_sv48x4() {
+        let StubGuestPageTable {
+            guest_page_table,
+            page_tracker,
+            mut host_pages,
+            mut pte_pages,
+            gpa_base,
+        } = stub_guest_page_table();
+        let id = PageOwnerId::host();
+
+        // Populate the tables for a page, then leave it unmapped. This leaves behind empty level
+        // 2, 1 and 0 tables.
+        let mapper = guest_page_table
+            .map_range(gpa_base, PageSize::Size4k, 1, &mut || pte_pages.next())
+            .unwrap();
+        drop(mapper);
+
+        let mut freed = Vec::new();
+        assert_eq!(guest_page_table.compact(&mut |p| freed.push(p.clean())), 3);
+        assert!(guest_page_table.range_is_empty(gpa_base, PageSize::Size4k as u64));
+
+        // The freed pages can be used to populate the tables again.
+        let page = page_tracker
+            .assign_page_for_mapping(host_pages.next().unwrap(), id)
+            .unwrap();
+        let mapper = guest_page_table
+            .map_range(gpa_base, PageSize::Size4k, 1, &mut || freed.pop())
+            .unwrap();
+        mapper.map_page(gpa_base, page).unwrap();
+        drop(mapper);
+        assert!(freed.is_empty());
+
+        // Nothing is freed while a table still maps a page.
+        assert_eq!(
+            guest_page_table.compact(&mut |_| panic!("nothing to free")),
+            0
+        );
+        let addr = RawAddr::guest(gpa_base.bits(), PageOwnerId::host());
+        assert_eq!(guest_page_table.walk_one(addr).unwrap().gpa(), gpa_base);
+    }
 }
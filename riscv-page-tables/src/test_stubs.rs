@@ -33,7 +33,9 @@ pub fn stub_sys_memory() -> StubState {
     };
     let mut hyp_mem = HypPageAlloc::new(&mut hw_map);
     let root_pages = hyp_mem.take_pages_for_host_state_with_alignment(4, Sv48x4::TOP_LEVEL_ALIGN);
-    let pte_pages = hyp_mem.take_pages_for_host_state(3);
+    // Enough page-table pages to map 1024 contiguous 4kB pages at a 2MB-aligned address: one L3 and
+    // one L2 table, and two L1 tables.
+    let pte_pages = hyp_mem.take_pages_for_host_state(4);
     let (page_tracker, host_pages) = PageTracker::from(hyp_mem, Sv48x4::TOP_LEVEL_ALIGN);
     // Leak the backing ram so it doesn't get freed
     std::mem::forget(backing_mem);
@@ -23,5 +23,7 @@ pub use page::*;
 pub use page_owner_id::{
     AddressSpace, GuestPhys, GuestVirt, PageOwnerId, SupervisorPhys, SupervisorVirt,
 };
-pub use sequential_pages::{Error as SequentialPagesError, SeqPageIter, SequentialPages};
+pub use sequential_pages::{
+    Error as SequentialPagesError, InvalidRange, SeqPageIter, SequentialPages,
+};
 pub use state::*;
@@ -33,7 +33,30 @@ pub enum PageSize {
     Size512G = 512 * 1024 * 1024 * 1024,
 }
 
+// All of the page math assumes page sizes are power-of-two multiples of 4kB; make sure a bad
+// page size fails the build rather than silently corrupting addresses.
+const _: () = {
+    assert!(PageSize::is_valid_size(PageSize::Size4k as u64));
+    assert!(PageSize::is_valid_size(PageSize::Size2M as u64));
+    assert!(PageSize::is_valid_size(PageSize::Size1G as u64));
+    assert!(PageSize::is_valid_size(PageSize::Size512G as u64));
+};
+
 impl PageSize {
+    /// Returns if `bytes` is usable as a page size, i.e. if it's a power of two and a multiple of
+    /// 4kB. Can be used in const context to check sizes at build time.
+    ///
+    /// # Example
+    ///
+    /// ```rust,compile_fail
+    /// use riscv_pages::PageSize;
+    /// // 12kB isn't a power of two, so this fails to build.
+    /// const _: () = assert!(PageSize::is_valid_size(3 * 4096));
+    /// ```
+    pub const fn is_valid_size(bytes: u64) -> bool {
+        bytes.is_power_of_two() && bytes % (PageSize::Size4k as u64) == 0
+    }
+
     /// Returns `val` divided by 4kB, rounded up.
     pub const fn num_4k_pages(val: u64) -> u64 {
         (val + PageSize::Size4k as u64 - 1) / (PageSize::Size4k as u64)
@@ -56,6 +79,12 @@ impl PageSize {
         (val + *self as u64 - 1) & !(*self as u64 - 1)
     }
 
+    /// Same as `round_up()`, but returns `None` if the result would overflow.
+    pub fn checked_round_up(&self, val: u64) -> Option<u64> {
+        val.checked_add(*self as u64 - 1)
+            .map(|v| v & !(*self as u64 - 1))
+    }
+
     /// Rounds down the quantity to the nearest multiple of this page size.
     ///
     /// # Example
@@ -195,6 +224,15 @@ impl<AS: AddressSpace> PageAddr<AS> {
         }
     }
 
+    /// Same as `with_round_up()`, but returns `None` if rounding up would overflow the address
+    /// space.
+    pub fn checked_round_up(addr: RawAddr<AS>, alignment: PageSize) -> Option<Self> {
+        let bits = alignment.checked_round_up(addr.bits())?;
+        Some(Self {
+            addr: RawAddr::new(bits, addr.address_space()),
+        })
+    }
+
     /// Same as above, but rounding down to the nearest multiple of the page size.
     pub fn with_round_down(addr: RawAddr<AS>, alignment: PageSize) -> Self {
         Self {
@@ -344,6 +382,14 @@ impl<AS: AddressSpace> PageAddrRange<AS> {
     pub fn page_size(&self) -> PageSize {
         self.page_size
     }
+
+    /// Returns true if this range and `other` have at least one byte in common.
+    pub fn overlaps(&self, other: &Self) -> bool {
+        let start = self.base.bits();
+        let other_start = other.base.bits();
+        start < other_start.saturating_add(other.length_bytes())
+            && other_start < start.saturating_add(self.length_bytes())
+    }
 }
 
 impl<AS: AddressSpace> Iterator for PageAddrRange<AS> {
@@ -445,6 +491,12 @@ pub trait PhysPage: Sized {
     fn pfn(&self) -> SupervisorPfn {
         self.addr().pfn()
     }
+
+    /// Returns the (exclusive) end address of this page. Saturates at `u64::MAX` for a page at the
+    /// very top of the address space.
+    fn end_addr(&self) -> u64 {
+        self.addr().bits().saturating_add(self.size() as u64)
+    }
 }
 
 /// Trait representing a page that can be cleaned to transform from a dirty to clean state.
@@ -556,6 +608,22 @@ impl<S: State> Page<S> {
         // guaranteed to be within the size of the page
         unsafe { slice::from_raw_parts(base_ptr, self.size as usize) }
     }
+
+    /// Returns true if every byte in the page is zero. Stops at the first non-zero word.
+    pub fn is_zero(&self) -> bool {
+        self.u64_iter().all(|v| v == 0)
+    }
+
+    /// Returns a fast, non-cryptographic (64-bit FNV-1a) hash of the contents of the page. Pages
+    /// with equal contents hash equally, so this can be used to cheaply find candidates for
+    /// identical pages before comparing their contents in full. Not suitable for measurement.
+    pub fn content_hash(&self) -> u64 {
+        const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const FNV_PRIME: u64 = 0x0100_0000_01b3;
+        self.as_bytes().iter().fold(FNV_OFFSET_BASIS, |hash, &b| {
+            (hash ^ b as u64).wrapping_mul(FNV_PRIME)
+        })
+    }
 }
 
 impl Page<InternalClean> {
@@ -753,6 +821,32 @@ mod tests {
         );
     }
 
+    #[test]
+    fn checked_round_up() {
+        let exact = RawAddr::supervisor(0x12_3000);
+        assert_eq!(
+            PageAddr::checked_round_up(exact, PageSize::Size4k).map(|a| a.bits()),
+            Some(0x12_3000)
+        );
+        let mid = RawAddr::supervisor(0x12_2345);
+        assert_eq!(
+            PageAddr::checked_round_up(mid, PageSize::Size4k).map(|a| a.bits()),
+            Some(0x12_3000)
+        );
+        assert_eq!(
+            PageAddr::checked_round_up(mid, PageSize::Size2M).map(|a| a.bits()),
+            Some(0x20_0000)
+        );
+        let top_page = RawAddr::supervisor(u64::MAX & !0xfff);
+        assert_eq!(
+            PageAddr::checked_round_up(top_page, PageSize::Size4k).map(|a| a.bits()),
+            Some(u64::MAX & !0xfff)
+        );
+        let ceiling = RawAddr::supervisor(u64::MAX - 0x10);
+        assert!(PageAddr::checked_round_up(ceiling, PageSize::Size4k).is_none());
+        assert_eq!(PageSize::Size4k.checked_round_up(u64::MAX), None);
+    }
+
     #[test]
     fn page_iter_start() {
         let addr4k = PageAddr::new(RawAddr::supervisor(0)).unwrap();
@@ -880,4 +974,89 @@ mod tests {
 
         assert!(page.as_bytes().last().unwrap() == &0xAA);
     }
+
+    #[test]
+    fn range_overlaps() {
+        let base = SupervisorPageAddr::new(RawAddr::supervisor(0x1000)).unwrap();
+        let range = SupervisorPageRange::new(base, 4);
+        let adjacent = SupervisorPageRange::new(base.checked_add_pages(4).unwrap(), 2);
+        let overlapping = SupervisorPageRange::new(base.checked_add_pages(3).unwrap(), 2);
+        assert!(range.overlaps(&range));
+        assert!(range.overlaps(&overlapping));
+        assert!(overlapping.overlaps(&range));
+        assert!(!range.overlaps(&adjacent));
+        assert!(!adjacent.overlaps(&range));
+        assert!(!range.overlaps(&SupervisorPageRange::new(base, 0)));
+    }
+
+    #[test]
+    fn measured_donation_overlaps() {
+        // Source and destination ranges of a measured page donation, as checked by
+        // `guest_add_measured_pages()`.
+        let id = PageOwnerId::new(2).unwrap();
+        let guest_range = |addr, num_pages| {
+            PageAddrRange::new(
+                GuestPageAddr::new(RawAddr::guest(addr, id)).unwrap(),
+                num_pages,
+            )
+        };
+        let dest = guest_range(0x8000_0000, 8);
+        // Source data starting in, ending in, or covering the donated pages is rejected.
+        assert!(guest_range(0x8000_7000, 8).overlaps(&dest));
+        assert!(guest_range(0x7fff_9000, 8).overlaps(&dest));
+        assert!(guest_range(0x8000_0000, 8).overlaps(&dest));
+        // Source data right before or after the donated pages is fine.
+        assert!(!guest_range(0x7fff_8000, 8).overlaps(&dest));
+        assert!(!guest_range(0x8000_8000, 8).overlaps(&dest));
+        // Ranges running up to the end of the address space don't wrap around.
+        let top = guest_range(u64::MAX & !0xfff, 1);
+        assert!(!top.overlaps(&guest_range(0, 1)));
+        assert!(top.overlaps(&top));
+    }
+
+    #[test]
+    fn page_end_addr() {
+        let addr = SupervisorPageAddr::new(RawAddr::supervisor(0x20_0000)).unwrap();
+        // Not safe, but memory won't be touched in the test...
+        let page: Page<ConvertedDirty> = unsafe { Page::new(addr) };
+        assert_eq!(page.end_addr(), 0x20_1000);
+        let huge_page: Page<ConvertedDirty> =
+            unsafe { Page::new_with_size(addr, PageSize::Size2M) };
+        assert_eq!(huge_page.end_addr(), 0x40_0000);
+    }
+
+    #[test]
+    fn content_hash_follows_contents() {
+        let fill = |val: u8| {
+            // Not safe, but the page is leaked by `new_in_test()` so the memory stays valid.
+            let page: Page<ConvertedClean> = unsafe { Page::new(Page::new_in_test().addr()) };
+            page.try_initialize(|bytes| {
+                bytes.fill(val);
+                Ok::<(), ()>(())
+            })
+            .map_err(|(e, _)| e)
+            .unwrap()
+        };
+        let a = fill(0xAA);
+        let b = fill(0xAA);
+        let c = fill(0x55);
+        assert_eq!(a.content_hash(), b.content_hash());
+        assert_ne!(a.content_hash(), c.content_hash());
+    }
+
+    #[test]
+    fn page_is_zero() {
+        let page = Page::new_in_test();
+        assert!(page.is_zero());
+        // Not safe, but the page is leaked by `new_in_test()` so the memory stays valid.
+        let page: Page<ConvertedClean> = unsafe { Page::new(page.addr()) };
+        let page = page
+            .try_initialize(|bytes| {
+                bytes[4095] = 1;
+                Ok::<(), ()>(())
+            })
+            .map_err(|(e, _)| e)
+            .unwrap();
+        assert!(!page.is_zero());
+    }
 }
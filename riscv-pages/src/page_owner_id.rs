@@ -116,3 +116,20 @@ pub type GuestPhys = Guest<Physical>;
 
 /// Represents a guest virtual address space.
 pub type GuestVirt = Guest<Virtual>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_round_trip() {
+        let id = PageOwnerId::new(0x1234).unwrap();
+        assert_eq!(PageOwnerId::new(id.raw()), Some(id));
+    }
+
+    #[test]
+    fn new_rejects_reserved() {
+        assert!(PageOwnerId::new(PageOwnerId::host().raw()).is_none());
+        assert!(PageOwnerId::new(PageOwnerId::hypervisor().raw()).is_none());
+    }
+}
@@ -2,12 +2,15 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
-use core::cmp::min;
+use core::cmp::{min, Ordering};
 use core::fmt;
 use core::marker::PhantomData;
 use core::num::NonZeroU64;
 
-use crate::page::{CleanablePhysPage, Page, PageSize, PhysPage, SupervisorPageAddr};
+use crate::page::{
+    CleanablePhysPage, InitializablePhysPage, Page, PageSize, PhysPage, SupervisorPageAddr,
+    SupervisorPageRange, SupervisorPhysAddr,
+};
 use crate::state::*;
 
 /// An error resulting from trying to convert an iterator of pages to `SequentialPages`.
@@ -22,10 +25,17 @@ pub enum Error<S: State, I: Iterator<Item = Page<S>>> {
     Overflow(I),
 }
 
-/// An error resulting from trying to create a `SequentialPages` from a range of pages that are not
-/// aligned to the requested size.
-#[derive(Clone, Copy, Debug)]
-pub struct UnalignedPages;
+/// An error resulting from trying to create a `SequentialPages` from a raw range of memory or from
+/// part of another `SequentialPages`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvalidRange {
+    /// The range isn't aligned to the requested page size.
+    Unaligned,
+    /// The range would overflow the address space.
+    Overflow,
+    /// The range extends past the end of the `SequentialPages` it was taken from.
+    OutOfBounds,
+}
 
 /// `SequentialPages` holds a range of consecutive pages of the same size and state. Each page's
 /// address is one page after the previous. This forms a contiguous area of memory suitable for
@@ -120,6 +130,91 @@ impl<S: State> SequentialPages<S> {
         self.page_size
     }
 
+    /// Returns the offset of `addr` from the start of the range, or `None` if `addr` isn't within
+    /// the range.
+    pub fn offset_of(&self, addr: SupervisorPhysAddr) -> Option<u64> {
+        let offset = addr.bits().checked_sub(self.addr.bits())?;
+        if offset < self.length_bytes() {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    /// Returns a raw pointer to the start of the contiguous memory region.
+    ///
+    /// The pointer is only valid for `length_bytes()` bytes and only while `self` is alive. The
+    /// caller must not write through it; use `as_mut_ptr()` for that.
+    pub fn as_ptr(&self) -> *const u8 {
+        self.addr.bits() as *const u8
+    }
+
+    /// Returns a mutable raw pointer to the start of the contiguous memory region.
+    ///
+    /// The pointer is only valid for `length_bytes()` bytes and only while `self` is alive. Since
+    /// `self` is mutably borrowed, the caller must not create any other references to the memory
+    /// while the pointer is in use.
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.addr.bits() as *mut u8
+    }
+
+    /// Test-only function that writes the address of each page into that page's first 8 bytes, so
+    /// that tests can later check which page ended up where.
+    #[cfg(test)]
+    pub fn fill_pattern(&mut self) {
+        for addr in self.page_addrs() {
+            // Safe because `self` uniquely owns the memory of each page and every page is aligned
+            // and large enough to hold a u64.
+            unsafe { core::ptr::write_volatile(addr.bits() as *mut u64, addr.bits()) };
+        }
+    }
+
+    /// Checks that the base of this range is aligned to `align` bytes. Returns `self` unchanged if
+    /// it is, otherwise hands `self` back as an error so the caller can retry with another range.
+    pub fn with_alignment(self, align: u64) -> Result<Self, Self> {
+        if self.addr.bits() % align == 0 {
+            Ok(self)
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Peels pages off the front of `self` until its base is aligned to `align` bytes, returning
+    /// the peeled-off pages, if any, along with the aligned remainder. Hands `self` back unchanged
+    /// if no page in the range starts at an `align`-aligned address.
+    pub fn align_base_to(mut self, align: u64) -> Result<(Option<Self>, Self), Self> {
+        let misalignment = self.addr.bits() % align;
+        if misalignment == 0 {
+            return Ok((None, self));
+        }
+        let skip_bytes = align - misalignment;
+        let skip = skip_bytes / self.page_size as u64;
+        if skip_bytes % self.page_size as u64 != 0 || skip >= self.count {
+            return Err(self);
+        }
+        // Unwrap ok: we've checked above that the aligned page is within the range.
+        let rest = self.split_off(skip).unwrap();
+        Ok((Some(self), rest))
+    }
+
+    // Checks that the range of `count` pages of `page_size` starting at `addr` is aligned to
+    // `page_size` and that its end address is representable, which rules out the last page of the
+    // address space.
+    fn check_range(
+        addr: SupervisorPageAddr,
+        page_size: PageSize,
+        count: u64,
+    ) -> Result<(), InvalidRange> {
+        if !addr.is_aligned(page_size) {
+            return Err(InvalidRange::Unaligned);
+        }
+        let len = count
+            .checked_mul(page_size as u64)
+            .ok_or(InvalidRange::Overflow)?;
+        addr.bits().checked_add(len).ok_or(InvalidRange::Overflow)?;
+        Ok(())
+    }
+
     /// Returns `SequentialPages` for the memory range provided.
     /// # Safety
     /// The range's ownership is given to `SequentialPages`, the caller must uniquely own that
@@ -128,10 +223,8 @@ impl<S: State> SequentialPages<S> {
         addr: SupervisorPageAddr,
         page_size: PageSize,
         count: u64,
-    ) -> Result<Self, UnalignedPages> {
-        if !addr.is_aligned(page_size) {
-            return Err(UnalignedPages);
-        }
+    ) -> Result<Self, InvalidRange> {
+        Self::check_range(addr, page_size, count)?;
         Ok(Self {
             addr,
             page_size,
@@ -140,6 +233,34 @@ impl<S: State> SequentialPages<S> {
         })
     }
 
+    /// Returns `SequentialPages` for the pages of size `page_size` at the addresses in `addrs`,
+    /// e.g. as recorded in a manifest. Returns `None` if `addrs` is empty, or if the addresses
+    /// aren't aligned to `page_size` or don't form a contiguous ascending range.
+    ///
+    /// # Safety
+    /// The pages' ownership is given to `SequentialPages`, the caller must uniquely own that
+    /// memory.
+    pub unsafe fn from_page_addrs(
+        addrs: &[SupervisorPageAddr],
+        page_size: PageSize,
+    ) -> Option<Self> {
+        let (&addr, rest) = addrs.split_first()?;
+        Self::check_range(addr, page_size, addrs.len() as u64).ok()?;
+        let mut last_addr = addr;
+        for &a in rest {
+            if last_addr.checked_add_pages_with_size(1, page_size) != Some(a) {
+                return None;
+            }
+            last_addr = a;
+        }
+        Some(Self {
+            addr,
+            page_size,
+            count: addrs.len() as u64,
+            state: PhantomData,
+        })
+    }
+
     /// Returns `SequentialPages` for the page range [start, end) with size `page_size`. Both
     /// start and end must be aligned to the requested size.
     ///
@@ -150,14 +271,16 @@ impl<S: State> SequentialPages<S> {
         start: SupervisorPageAddr,
         end: SupervisorPageAddr,
         page_size: PageSize,
-    ) -> Result<Self, UnalignedPages> {
-        if !start.is_aligned(page_size) || !end.is_aligned(page_size) {
-            return Err(UnalignedPages);
+    ) -> Result<Self, InvalidRange> {
+        if !end.is_aligned(page_size) {
+            return Err(InvalidRange::Unaligned);
         }
+        let count = end.bits().checked_sub(start.bits()).unwrap() / page_size as u64;
+        Self::check_range(start, page_size, count)?;
         Ok(Self {
             addr: start,
             page_size,
-            count: end.bits().checked_sub(start.bits()).unwrap() / page_size as u64,
+            count,
             state: PhantomData,
         })
     }
@@ -167,6 +290,110 @@ impl<S: State> SequentialPages<S> {
         self.addr.iter_from().take(self.count as usize)
     }
 
+    /// Returns an iterator over all the overlapping sub-ranges of `size` pages in `self`, in the
+    /// same manner as `slice::windows()`. Yields nothing if `self` holds fewer than `size` pages.
+    pub fn windows(&self, size: NonZeroU64) -> impl Iterator<Item = SupervisorPageRange> {
+        let addr = self.addr;
+        let page_size = self.page_size;
+        let num_windows = (self.count + 1).saturating_sub(size.get());
+        (0..num_windows).map(move |i| {
+            // Unwraps ok: every window lies within `self`, which is `page_size`-aligned and
+            // guaranteed by the constructor not to overflow.
+            let base = addr.checked_add_pages_with_size(i, page_size).unwrap();
+            SupervisorPageRange::with_size(base, size.get(), page_size).unwrap()
+        })
+    }
+
+    /// Shortens `self` to its first `at` pages, returning the remaining pages as a separate
+    /// `SequentialPages`. Fails with `InvalidRange::OutOfBounds`, leaving `self` untouched, if `at`
+    /// is beyond the end.
+    pub fn split_off(&mut self, at: u64) -> Result<Self, InvalidRange> {
+        if at > self.count {
+            return Err(InvalidRange::OutOfBounds);
+        }
+        // An empty tail is based at `self`'s base rather than its end, which may not be
+        // representable if `self` wasn't built through `check_range()`.
+        let addr = if at == self.count {
+            self.addr
+        } else {
+            // Unwrap ok: the page at `at` lies within `self`.
+            self.addr
+                .checked_add_pages_with_size(at, self.page_size)
+                .unwrap()
+        };
+        let tail = Self {
+            addr,
+            page_size: self.page_size,
+            count: self.count - at,
+            state: PhantomData,
+        };
+        self.count = at;
+        Ok(tail)
+    }
+
+    /// Peels a single 2MB page off the front of a range of 4kB pages, returning it along with the
+    /// remaining 4kB pages. Hands `self` back unchanged if it isn't made of 4kB pages, isn't 2MB
+    /// aligned, or is shorter than 2MB.
+    pub fn split_first_2m(mut self) -> Result<(Page<S>, Self), Self> {
+        let pages_per_2m = PageSize::num_4k_pages(PageSize::Size2M as u64);
+        if self.page_size != PageSize::Size4k
+            || !self.addr.is_aligned(PageSize::Size2M)
+            || self.count < pages_per_2m
+        {
+            return Err(self);
+        }
+        // Unwrap ok: we've checked above that `self` holds at least `pages_per_2m` pages.
+        let rest = self.split_off(pages_per_2m).unwrap();
+        // Safe since `self` uniquely owns the 2MB of memory at its base, which we've checked to be
+        // 2MB aligned.
+        let page = unsafe { Page::new_with_size(self.addr, PageSize::Size2M) };
+        Ok((page, rest))
+    }
+
+    /// Consumes `self`, returning its pages as an array of exactly `N` pages. Hands `self` back
+    /// unchanged if it doesn't hold exactly `N` pages.
+    pub fn into_array<const N: usize>(self) -> Result<[Page<S>; N], Self> {
+        if self.count != N as u64 {
+            return Err(self);
+        }
+        let mut pages = self.into_iter();
+        // Unwrap ok: we've checked above that there are exactly `N` pages.
+        Ok(core::array::from_fn(|_| pages.next().unwrap()))
+    }
+
+    /// Consumes `self`, copying the page-sized chunk of `data` destined for each page into it and
+    /// returning an iterator over the initialized pages paired with their chunks. The remainder of
+    /// the last partially-filled page, and any pages past the end of `data`, are zero-filled.
+    ///
+    /// Hands `self` back if `data` doesn't fit in `self`.
+    pub fn zip_pages<'a>(
+        self,
+        data: &'a [u8],
+    ) -> Result<impl Iterator<Item = (Page<ConvertedInitialized>, &'a [u8])> + 'a, Self>
+    where
+        S: Initializable + 'a,
+    {
+        if data.len() as u64 > self.length_bytes() {
+            return Err(self);
+        }
+        let chunks = data
+            .chunks(self.page_size as usize)
+            .chain(core::iter::repeat(&[][..]));
+        Ok(self.into_iter().zip(chunks).map(|(page, chunk)| {
+            let result = page.try_initialize(|bytes| {
+                let (head, tail) = bytes.split_at_mut(chunk.len());
+                head.copy_from_slice(chunk);
+                tail.fill(0);
+                Ok::<(), ()>(())
+            });
+            match result {
+                Ok(page) => (page, chunk),
+                // The initializer above never fails.
+                Err(_) => unreachable!(),
+            }
+        }))
+    }
+
     /// Return an iterator whose elements are `SequentialPages` of `chunk_size` pages. The last
     /// entry might contain fewer pages. This consumes `self`.
     pub fn into_chunks_iter(self, chunk_size: NonZeroU64) -> SeqPageChunkIter<S> {
@@ -202,6 +429,32 @@ impl<S: State> From<Page<S>> for SequentialPages<S> {
     }
 }
 
+impl<S: State> PartialEq for SequentialPages<S> {
+    fn eq(&self, other: &Self) -> bool {
+        self.addr == other.addr && self.count == other.count && self.page_size == other.page_size
+    }
+}
+
+impl<S: State> Eq for SequentialPages<S> {}
+
+impl<S: State> PartialOrd for SequentialPages<S> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<S: State> Ord for SequentialPages<S> {
+    /// Orders by base address, then by number of pages. The page size breaks any remaining ties so
+    /// that the ordering is consistent with equality.
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.addr
+            .bits()
+            .cmp(&other.addr.bits())
+            .then(self.count.cmp(&other.count))
+            .then(self.page_size.cmp(&other.page_size))
+    }
+}
+
 /// An iterator of the individual pages previously used to build a `SequentialPages` struct.
 /// Used to reclaim the pages from `SequentialPages`, returned from `SequentialPages::into_iter`.
 pub struct SeqPageIter<S: State> {
@@ -301,6 +554,7 @@ impl<S: State> ExactSizeIterator for SeqPageChunkIter<S> {}
 mod tests {
     use super::*;
     use crate::{PageAddr, RawAddr};
+    use std::vec::Vec;
 
     const TWO_MEG: u64 = 0x20_0000;
 
@@ -621,17 +875,17 @@ mod tests {
 
     #[test]
     fn chunks_iterator_at_last_page() {
-        // Test chunk iterator page limit: the last page of the sequence is at the last possible
-        // page address of a u64 address space. This is to check we can safely iterate over
-        // sequences of any u64 address.
+        // Test chunk iterator page limit: the sequence ends with the last page whose end address
+        // is representable, right below the last page of the u64 address space. This is to check
+        // we can safely iterate over sequences of any valid u64 address.
         let seq_len: u64 = 16;
         let base_addr = PageAddr::new(RawAddr::supervisor(
-            u64::MAX - seq_len * PageSize::Size4k as u64 + 1,
+            u64::MAX - (seq_len + 1) * PageSize::Size4k as u64 + 1,
         ))
         .unwrap();
         let chunk_size = NonZeroU64::new(1).unwrap();
 
-        // Create a SequentialPages of length 16 at `(u64::MAX - 16 * PageSize::Size4k)`
+        // Create a SequentialPages of length 16 at `(u64::MAX - 17 * PageSize::Size4k)`
         let seq = create_test_sequential_pages(base_addr, seq_len, PageSize::Size4k);
         let page_size = seq.page_size();
 
@@ -660,17 +914,17 @@ mod tests {
 
     #[test]
     fn chunks_iterator_at_last_page_huge() {
-        // Test chunk iterator page limit: the last page of the sequence is at the last possible
-        // page address of a u64 address space. This is to check we can safely iterate over
-        // sequences of any u64 address.
+        // Test chunk iterator page limit: the sequence ends with the last page whose end address
+        // is representable, right below the last page of the u64 address space. This is to check
+        // we can safely iterate over sequences of any valid u64 address.
         let seq_len: u64 = 16;
         let base_addr = PageAddr::new(RawAddr::supervisor(
-            u64::MAX - seq_len * PageSize::Size2M as u64 + 1,
+            u64::MAX - (seq_len + 1) * PageSize::Size2M as u64 + 1,
         ))
         .unwrap();
         let chunk_size = NonZeroU64::new(1).unwrap();
 
-        // Create a SequentialPages of length 16 at `(u64::MAX - 16 * PageSize::Size4k)`
+        // Create a SequentialPages of length 16 at `(u64::MAX - 17 * PageSize::Size2M)`
         let seq = create_test_sequential_pages(base_addr, seq_len, PageSize::Size2M);
         let page_size = seq.page_size();
 
@@ -697,6 +951,65 @@ mod tests {
         assert!(iter.next().is_none());
     }
 
+    #[test]
+    fn with_alignment_aligned() {
+        let base_addr = PageAddr::new(RawAddr::supervisor(0x4000)).unwrap();
+        let seq = create_test_sequential_pages(base_addr, 4, PageSize::Size4k);
+        let seq = seq.with_alignment(0x4000).unwrap();
+        assert_eq!(seq.base(), base_addr);
+        assert_eq!(seq.len(), 4);
+    }
+
+    #[test]
+    fn with_alignment_misaligned() {
+        let base_addr = PageAddr::new(RawAddr::supervisor(0x1000)).unwrap();
+        let seq = create_test_sequential_pages(base_addr, 4, PageSize::Size4k);
+        let seq = seq.with_alignment(0x4000).unwrap_err();
+        assert_eq!(seq.base(), base_addr);
+        assert_eq!(seq.len(), 4);
+    }
+
+    #[test]
+    fn sort_by_base() {
+        let mut regions = vec![
+            create_test_sequential_pages(
+                PageAddr::new(RawAddr::supervisor(0x8000)).unwrap(),
+                2,
+                PageSize::Size4k,
+            ),
+            create_test_sequential_pages(
+                PageAddr::new(RawAddr::supervisor(0x1000)).unwrap(),
+                4,
+                PageSize::Size4k,
+            ),
+            create_test_sequential_pages(
+                PageAddr::new(RawAddr::supervisor(0x1000)).unwrap(),
+                1,
+                PageSize::Size4k,
+            ),
+            create_test_sequential_pages(
+                PageAddr::new(RawAddr::supervisor(0x6000)).unwrap(),
+                1,
+                PageSize::Size4k,
+            ),
+        ];
+        regions.sort();
+        let sorted: Vec<(u64, u64)> = regions.iter().map(|r| (r.base().bits(), r.len())).collect();
+        assert_eq!(
+            sorted,
+            vec![(0x1000, 1), (0x1000, 4), (0x6000, 1), (0x8000, 2)]
+        );
+        assert_eq!(
+            regions[0],
+            create_test_sequential_pages(
+                PageAddr::new(RawAddr::supervisor(0x1000)).unwrap(),
+                1,
+                PageSize::Size4k,
+            )
+        );
+        assert_ne!(regions[0], regions[1]);
+    }
+
     #[test]
     fn chunks_iterator_with_empty_sequence() {
         // Test chunk iterator with an empty sequence.
@@ -709,4 +1022,278 @@ mod tests {
         // Check that we can't iterate on an empty Sequence.
         assert!(iter.next().is_none());
     }
+
+    #[test]
+    fn zip_pages_tiles_data() {
+        const NUM_PAGES: usize = 4;
+        let mem = vec![0xffu8; (NUM_PAGES + 1) * PageSize::Size4k as usize];
+        let ptr = mem.as_ptr();
+        // Safe because the above allocation guarantees that the result is still a valid pointer.
+        let aligned_ptr = unsafe { ptr.add(ptr.align_offset(PageSize::Size4k as usize)) };
+        let base_addr = PageAddr::new(RawAddr::supervisor(aligned_ptr as u64)).unwrap();
+        // Leak the memory so that the pages stay valid.
+        core::mem::forget(mem);
+        let seq = create_test_sequential_pages(base_addr, NUM_PAGES as u64, PageSize::Size4k);
+        // Two and a half pages worth of data.
+        let data: Vec<u8> = (0..0x2800).map(|i| (i % 251) as u8 + 1).collect();
+
+        let mut copied = Vec::new();
+        let mut chunks = Vec::new();
+        let mut addr = base_addr;
+        for (page, chunk) in seq.zip_pages(&data).ok().unwrap() {
+            assert_eq!(page.addr(), addr);
+            copied.extend_from_slice(page.as_bytes());
+            chunks.extend_from_slice(chunk);
+            addr = addr.checked_add_pages(1).unwrap();
+        }
+
+        // The chunks tile `data` exactly, and the pages hold them followed by zeroes only.
+        assert_eq!(chunks, data);
+        assert_eq!(copied.len(), NUM_PAGES * PageSize::Size4k as usize);
+        assert_eq!(&copied[..data.len()], &data[..]);
+        assert!(copied[data.len()..].iter().all(|&b| b == 0));
+
+        // Data that doesn't fit is rejected without touching the pages.
+        let seq = create_test_sequential_pages(base_addr, 2, PageSize::Size4k);
+        let seq = seq.zip_pages(&data).err().unwrap();
+        assert_eq!(seq.len(), 2);
+        assert_eq!(
+            seq.into_iter().next().unwrap().get_u64(0),
+            Some(0x0807060504030201)
+        );
+    }
+
+    #[test]
+    fn split_off_suffix() {
+        let base_addr = PageAddr::new(RawAddr::supervisor(0x1000)).unwrap();
+        let mut seq = create_test_sequential_pages(base_addr, 6, PageSize::Size4k);
+        assert_eq!(seq.split_off(7).err(), Some(InvalidRange::OutOfBounds));
+        assert_eq!(seq.len(), 6);
+
+        let tail = seq.split_off(2).unwrap();
+        assert_eq!(seq.base(), base_addr);
+        assert_eq!(seq.len(), 2);
+        assert_eq!(tail.base(), base_addr.checked_add_pages(2).unwrap());
+        assert_eq!(tail.len(), 4);
+
+        // Splitting at the end leaves an empty tail.
+        let empty = seq.split_off(seq.len()).unwrap();
+        assert!(empty.is_empty());
+        assert_eq!(seq.len(), 2);
+
+        // That holds even for a page at the top of the address space, whose end wraps around.
+        let top_addr = PageAddr::new(RawAddr::supervisor(u64::MAX & !0xfff)).unwrap();
+        // Not safe, but the page is never accessed.
+        let mut top = SequentialPages::<ConvertedDirty>::from(unsafe { Page::new(top_addr) });
+        let empty = top.split_off(top.len()).unwrap();
+        assert!(empty.is_empty());
+        assert_eq!(top.base(), top_addr);
+        assert_eq!(top.len(), 1);
+    }
+
+    #[test]
+    fn raw_ptr_matches_base() {
+        let base_addr = PageAddr::new(RawAddr::supervisor(0x1000)).unwrap();
+        let mut seq = create_test_sequential_pages(base_addr, 2, PageSize::Size4k);
+        assert_eq!(seq.as_ptr() as u64, seq.base().bits());
+        assert_eq!(seq.as_mut_ptr() as u64, seq.base().bits());
+    }
+
+    #[test]
+    fn windows_overlap() {
+        let base_addr = PageAddr::new(RawAddr::supervisor(0x1000)).unwrap();
+        let seq = create_test_sequential_pages(base_addr, 5, PageSize::Size4k);
+        let windows: Vec<_> = seq.windows(NonZeroU64::new(2).unwrap()).collect();
+        assert_eq!(windows.len(), 4);
+        for (i, w) in windows.iter().enumerate() {
+            assert_eq!(w.base(), base_addr.checked_add_pages(i as u64).unwrap());
+            assert_eq!(w.num_pages(), 2);
+        }
+        assert_eq!(seq.windows(NonZeroU64::new(6).unwrap()).count(), 0);
+    }
+
+    #[test]
+    fn split_first_2m() {
+        const PAGES_PER_2M: u64 = 512;
+        let base_addr = PageAddr::new(RawAddr::supervisor(TWO_MEG)).unwrap();
+        let seq = create_test_sequential_pages(base_addr, PAGES_PER_2M + 3, PageSize::Size4k);
+        let (page, rest) = seq.split_first_2m().ok().unwrap();
+        assert_eq!(page.addr(), base_addr);
+        assert_eq!(page.size(), PageSize::Size2M);
+        assert_eq!(rest.base().bits(), 2 * TWO_MEG);
+        assert_eq!(rest.len(), 3);
+        assert_eq!(rest.page_size(), PageSize::Size4k);
+
+        // Exactly 2MB, at the top of the address space, leaves nothing behind.
+        let top_2m = u64::MAX - TWO_MEG + 1;
+        // Not safe, but the pages are never accessed.
+        let pages = (0..PAGES_PER_2M).map(|i| unsafe {
+            Page::<ConvertedDirty>::new(
+                PageAddr::new(RawAddr::supervisor(top_2m + i * PageSize::Size4k as u64)).unwrap(),
+            )
+        });
+        let seq = SequentialPages::from_pages(pages).ok().unwrap();
+        let (page, rest) = seq.split_first_2m().ok().unwrap();
+        assert_eq!(page.addr().bits(), top_2m);
+        assert!(rest.is_empty());
+
+        // Too short.
+        let seq = create_test_sequential_pages(base_addr, PAGES_PER_2M - 1, PageSize::Size4k);
+        let seq = seq.split_first_2m().err().unwrap();
+        assert_eq!(seq.len(), PAGES_PER_2M - 1);
+
+        // Misaligned.
+        let unaligned_addr = base_addr.checked_add_pages(1).unwrap();
+        let seq = create_test_sequential_pages(unaligned_addr, PAGES_PER_2M, PageSize::Size4k);
+        let seq = seq.split_first_2m().err().unwrap();
+        assert_eq!(seq.base(), unaligned_addr);
+    }
+
+    #[test]
+    fn fill_pattern() {
+        const NUM_PAGES: usize = 4;
+        let mem = vec![0u8; (NUM_PAGES + 1) * PageSize::Size4k as usize];
+        let ptr = mem.as_ptr();
+        // Safe because the above allocation guarantees that the result is still a valid pointer.
+        let aligned_ptr = unsafe { ptr.add(ptr.align_offset(PageSize::Size4k as usize)) };
+        let base_addr = PageAddr::new(RawAddr::supervisor(aligned_ptr as u64)).unwrap();
+        // Leak the memory so that the pages stay valid.
+        core::mem::forget(mem);
+        let mut seq = create_test_sequential_pages(base_addr, NUM_PAGES as u64, PageSize::Size4k);
+        seq.fill_pattern();
+        for page in seq {
+            assert_eq!(page.get_u64(0), Some(page.addr().bits()));
+            assert_eq!(page.get_u64(1), Some(0));
+        }
+    }
+
+    #[test]
+    fn offset_of() {
+        let base_addr = PageAddr::new(RawAddr::supervisor(0x1000)).unwrap();
+        let seq = create_test_sequential_pages(base_addr, 4, PageSize::Size4k);
+        assert_eq!(seq.offset_of(RawAddr::supervisor(0x1000)), Some(0));
+        assert_eq!(seq.offset_of(RawAddr::supervisor(0x2345)), Some(0x1345));
+        assert_eq!(seq.offset_of(RawAddr::supervisor(0x4fff)), Some(0x3fff));
+        assert_eq!(seq.offset_of(RawAddr::supervisor(0x5000)), None);
+        assert_eq!(seq.offset_of(RawAddr::supervisor(0xfff)), None);
+    }
+
+    #[test]
+    fn align_base_to() {
+        let base_addr = PageAddr::new(RawAddr::supervisor(TWO_MEG - 0x1000)).unwrap();
+        let seq = create_test_sequential_pages(base_addr, 4, PageSize::Size4k);
+        let (prefix, rest) = seq.align_base_to(TWO_MEG).unwrap();
+        let prefix = prefix.unwrap();
+        assert_eq!(prefix.base(), base_addr);
+        assert_eq!(prefix.len(), 1);
+        assert_eq!(rest.base().bits(), TWO_MEG);
+        assert_eq!(rest.len(), 3);
+
+        // Already aligned.
+        let (prefix, rest) = rest.align_base_to(TWO_MEG).unwrap();
+        assert!(prefix.is_none());
+        assert_eq!(rest.base().bits(), TWO_MEG);
+        assert_eq!(rest.len(), 3);
+
+        // No aligned page in the range.
+        let seq = create_test_sequential_pages(base_addr, 1, PageSize::Size4k);
+        let seq = seq.align_base_to(TWO_MEG).err().unwrap();
+        assert_eq!(seq.base(), base_addr);
+        assert_eq!(seq.len(), 1);
+    }
+
+    #[test]
+    fn check_range() {
+        let base_addr = PageAddr::new(RawAddr::supervisor(0x1000)).unwrap();
+        assert_eq!(
+            SequentialPages::<ConvertedDirty>::check_range(base_addr, PageSize::Size4k, 4),
+            Ok(())
+        );
+        assert_eq!(
+            SequentialPages::<ConvertedDirty>::check_range(base_addr, PageSize::Size2M, 4),
+            Err(InvalidRange::Unaligned)
+        );
+        assert_eq!(
+            SequentialPages::<ConvertedDirty>::check_range(base_addr, PageSize::Size4k, u64::MAX),
+            Err(InvalidRange::Overflow)
+        );
+        // The range must end below the top of the address space, so even the last page on its own
+        // is rejected.
+        let top_addr = PageAddr::new(RawAddr::supervisor(u64::MAX & !0xfff)).unwrap();
+        assert_eq!(
+            SequentialPages::<ConvertedDirty>::check_range(top_addr, PageSize::Size4k, 1),
+            Err(InvalidRange::Overflow)
+        );
+        let below_top = PageAddr::new(RawAddr::supervisor(top_addr.bits() - 0x1000)).unwrap();
+        assert_eq!(
+            SequentialPages::<ConvertedDirty>::check_range(below_top, PageSize::Size4k, 1),
+            Ok(())
+        );
+        // Not safe, but the range is never accessed.
+        let pages = unsafe {
+            SequentialPages::<ConvertedDirty>::from_mem_range(top_addr, PageSize::Size4k, 1)
+        };
+        assert_eq!(pages.err(), Some(InvalidRange::Overflow));
+    }
+
+    #[test]
+    fn raw_constructors_check_range() {
+        type Pages = SequentialPages<ConvertedDirty>;
+        let page_addr = |a| PageAddr::new(RawAddr::supervisor(a)).unwrap();
+        let unaligned = page_addr(0x20_1000);
+        let aligned = page_addr(0x40_0000);
+        let end = page_addr(0x80_0000);
+        // Not safe, but none of the ranges are ever accessed.
+        unsafe {
+            let pages = Pages::from_page_range(unaligned, end, PageSize::Size2M);
+            assert_eq!(pages.err(), Some(InvalidRange::Unaligned));
+            let pages = Pages::from_page_range(aligned, end, PageSize::Size2M).unwrap();
+            assert_eq!(pages.len(), 2);
+
+            assert!(Pages::from_page_addrs(&[unaligned], PageSize::Size2M).is_none());
+            let addrs = [aligned, page_addr(0x60_0000)];
+            let pages = Pages::from_page_addrs(&addrs, PageSize::Size2M).unwrap();
+            assert_eq!(pages.len(), 2);
+        }
+    }
+
+    #[test]
+    fn into_array() {
+        let base_addr = PageAddr::new(RawAddr::supervisor(0x1000)).unwrap();
+        let seq = create_test_sequential_pages(base_addr, 4, PageSize::Size4k);
+        let pages: [Page<ConvertedDirty>; 4] = seq.into_array().ok().unwrap();
+        for (i, page) in pages.iter().enumerate() {
+            assert_eq!(page.addr(), base_addr.checked_add_pages(i as u64).unwrap());
+        }
+
+        let seq = create_test_sequential_pages(base_addr, 3, PageSize::Size4k);
+        let seq = seq.into_array::<4>().err().unwrap();
+        assert_eq!(seq.base(), base_addr);
+        assert_eq!(seq.len(), 3);
+    }
+
+    #[test]
+    fn from_page_addrs() {
+        let base_addr = PageAddr::new(RawAddr::supervisor(0x1000)).unwrap();
+        let addrs: Vec<_> = base_addr.iter_from().take(4).collect();
+        // NOT SAFE, but this is a test
+        let seq: SequentialPages<ConvertedDirty> =
+            unsafe { SequentialPages::from_page_addrs(&addrs, PageSize::Size4k).unwrap() };
+        assert_eq!(seq.base(), base_addr);
+        assert_eq!(seq.len(), 4);
+
+        let gapped = [base_addr, base_addr.checked_add_pages(2).unwrap()];
+        assert!(unsafe {
+            SequentialPages::<ConvertedDirty>::from_page_addrs(&gapped, PageSize::Size4k)
+        }
+        .is_none());
+        assert!(unsafe {
+            SequentialPages::<ConvertedDirty>::from_page_addrs(&addrs[..1], PageSize::Size2M)
+        }
+        .is_none());
+        assert!(unsafe {
+            SequentialPages::<ConvertedDirty>::from_page_addrs(&[], PageSize::Size4k)
+        }
+        .is_none());
+    }
 }
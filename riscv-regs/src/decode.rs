@@ -44,3 +44,27 @@ impl DecodedInstruction {
         self.len
     }
 }
+
+/// Returns true if `htinst` holds one of the pseudoinstructions written by hardware when a G-stage
+/// fault is taken by an implicit memory access of the VS-stage page table walk. Bit 5 distinguishes
+/// reads from writes (for A/D updates); bit 12 distinguishes 32 and 64-bit PTEs.
+pub fn is_implicit_pt_access(htinst: u64) -> bool {
+    matches!(htinst & !0x20, 0x2000 | 0x3000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn implicit_pt_access() {
+        // Reads and writes of 32 and 64-bit PTEs.
+        for htinst in [0x2000, 0x2020, 0x3000, 0x3020] {
+            assert!(is_implicit_pt_access(htinst));
+        }
+        // No information, a transformed `ld a0, 0(x0)`, and other bits set.
+        for htinst in [0, 0x3503, 0x1000, 0x2001, 0x12000] {
+            assert!(!is_implicit_pt_access(htinst));
+        }
+    }
+}
@@ -3,6 +3,7 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use arrayvec::{ArrayString, ArrayVec};
+use attestation::{Error as AttestationError, MeasurementLog, Result as AttestationResult};
 use core::{fmt, num, ops::ControlFlow, slice};
 use device_tree::{DeviceTree, DeviceTreeResult, DeviceTreeSerializer};
 use drivers::{imsic::*, iommu::*, pci::*, CpuId, CpuInfo};
@@ -302,8 +303,10 @@ impl<T: GuestStagePagingMode> HostVmLoader<T> {
             SequentialPages::from_mem_range(self.kernel.base(), PageSize::Size4k, num_kernel_pages)
                 .unwrap()
         };
+        // Unwrap ok for the measured regions below: the measurement log has room for each of them.
         self.vm
-            .add_measured_pages(current_gpa, kernel_pages.into_iter());
+            .add_measured_pages(current_gpa, kernel_pages.into_iter())
+            .unwrap();
         current_gpa = current_gpa.checked_add_pages(num_kernel_pages).unwrap();
 
         if let Some(r) = self.initramfs {
@@ -319,7 +322,8 @@ impl<T: GuestStagePagingMode> HostVmLoader<T> {
                     .unwrap()
             };
             self.vm
-                .add_measured_pages(current_gpa, initramfs_pages.into_iter());
+                .add_measured_pages(current_gpa, initramfs_pages.into_iter())
+                .unwrap();
             current_gpa = current_gpa.checked_add_pages(num_initramfs_pages).unwrap();
         }
 
@@ -334,7 +338,8 @@ impl<T: GuestStagePagingMode> HostVmLoader<T> {
         };
         let num_fdt_pages = fdt_pages.len();
         self.vm
-            .add_measured_pages(current_gpa, fdt_pages.into_iter());
+            .add_measured_pages(current_gpa, fdt_pages.into_iter())
+            .unwrap();
         current_gpa = current_gpa.checked_add_pages(num_fdt_pages).unwrap();
 
         self.vm
@@ -589,9 +594,17 @@ impl VmCpuExitReporting for HostVmRunner {
 // Pages used by the `PageVec` for the Host VM guest tracking.
 const HOSTVM_GUEST_TRACKING_PAGES: usize = 2;
 
+// The number of measured regions in the host VM: the kernel, the initramfs and the FDT.
+const HOSTVM_MEASURED_REGIONS: usize = 3;
+
+/// The log of the regions measured into the host VM.
+pub type HostMeasurementLog = MeasurementLog<sha2::Sha384, HOSTVM_MEASURED_REGIONS>;
+
 /// Represents the special VM that serves as the host for the system.
 pub struct HostVm<T: GuestStagePagingMode> {
     inner: GuestVm<T>,
+    // The regions of the host VM that were measured, in the order that they were measured.
+    measurement_log: HostMeasurementLog,
 }
 
 impl<T: GuestStagePagingMode> HostVm<T> {
@@ -651,7 +664,10 @@ impl<T: GuestStagePagingMode> HostVm<T> {
 
         // Unwrap okay, we allocated 'GuestVm::<T>::required_pages()` pages.
         let inner = GuestVm::new(vm, vm_state_pages).unwrap();
-        let this = Self { inner };
+        let this = Self {
+            inner,
+            measurement_log: HostMeasurementLog::new(),
+        };
 
         {
             let init_vm = this.inner.as_initializing_vm().unwrap();
@@ -691,14 +707,23 @@ impl<T: GuestStagePagingMode> HostVm<T> {
         vm.vm_pages().add_pci_region(addr, len).unwrap();
     }
 
-    // Adds data pages that are measured and mapped to the page tables for the host. Requires
-    // that the GPA map the SPA in T::TOP_LEVEL_ALIGN-aligned contiguous chunks.
-    fn add_measured_pages<I, S, M>(&mut self, to_addr: GuestPageAddr, pages: I)
+    // Adds data pages that are measured and mapped to the page tables for the host, recording them
+    // in the measurement log. Requires that the GPA map the SPA in T::TOP_LEVEL_ALIGN-aligned
+    // contiguous chunks.
+    fn add_measured_pages<I, S, M>(
+        &mut self,
+        to_addr: GuestPageAddr,
+        pages: I,
+    ) -> AttestationResult<()>
     where
         I: ExactSizeIterator<Item = Page<S>>,
         S: Assignable<M>,
         M: MeasureRequirement,
     {
+        if self.measurement_log.is_full() {
+            return Err(AttestationError::MeasurementLogFull);
+        }
+        let num_pages = pages.len() as u64;
         let vm = self.inner.as_initializing_vm().unwrap();
         let page_tracker = vm.page_tracker();
         // Unwrap ok since we've donate sufficient PT pages to map the entire address space up front.
@@ -719,6 +744,17 @@ impl<T: GuestStagePagingMode> HostVm<T> {
                 .map_page(vm_addr, mappable, vm.attestation_mgr())
                 .unwrap();
         }
+        self.measurement_log
+            .record(vm.attestation_mgr(), to_addr.bits(), num_pages)
+    }
+
+    /// Returns the log of the regions of the host VM's address space that went into its
+    /// measurement. Together with the images loaded into these regions, this allows the host VM's
+    /// measurement to be reproduced.
+    // Not consumed by the hypervisor itself; the log is for verifiers of the host's measurement.
+    #[allow(dead_code)]
+    pub fn measurement_log(&self) -> &HostMeasurementLog {
+        &self.measurement_log
     }
 
     // Adds the IMSIC pages for `cpu` to the host. The first page in `pages` is set as the
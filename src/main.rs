@@ -500,6 +500,11 @@ extern "C" fn kernel_init(hart_id: u64, fdt_addr: u64) {
     UmodeTask::send_req(u_mode_api::UmodeRequest::hello()).unwrap();
     UmodeTask::send_req(u_mode_api::UmodeRequest::nop()).unwrap();
 
+    println!(
+        "Memory remaining for the host VM: {} MiB",
+        hyp_mem.bytes_remaining() / (1024 * 1024)
+    );
+
     // Now load the host VM.
     let host = HostVmLoader::new(
         hyp_dt,
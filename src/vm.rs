@@ -7,7 +7,7 @@ use core::{mem, ops::ControlFlow, slice};
 use der::Decode;
 use drivers::{imsic::*, pmu::PmuInfo};
 use page_tracking::collections::PageBox;
-use page_tracking::{LockedPageList, PageList, PageTracker, TlbVersion};
+use page_tracking::{LockedPageList, PageFaultType, PageList, PageTracker, TlbVersion};
 use rice::x509::{request::CertReq, MAX_CSR_LEN};
 use riscv_page_tables::{GuestStagePageTable, GuestStagePagingMode};
 use riscv_pages::*;
@@ -21,7 +21,7 @@ use crate::umode::UmodeTask;
 use crate::vm_cpu::{ActiveVmCpu, VmCpu, VmCpuParent, VmCpuStatus, VmCpuTrap, VmCpus, VM_CPUS_MAX};
 use crate::vm_pages::Error as VmPagesError;
 use crate::vm_pages::{
-    ActiveVmPages, AnyVmPages, GuestUmodeMapping, InstructionFetchError, PageFaultType, VmPages,
+    ActiveVmPages, AnyVmPages, FaultLoop, GuestUmodeMapping, InstructionFetchError, VmPages,
     VmPagesRef,
 };
 
@@ -523,10 +523,22 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
                     fault_addr,
                     fault_pc,
                     priv_level,
+                    guest_pt_walk,
                 } => {
-                    let pf = active_vcpu
-                        .active_pages()
-                        .get_page_fault_cause(exception, fault_addr);
+                    let result =
+                        active_vcpu.handle_page_fault(exception, fault_addr, guest_pt_walk);
+                    let pf = match result {
+                        Ok(Some(pf)) => pf,
+                        // The fault was resolved without involving the host; just retry.
+                        Ok(None) => continue,
+                        Err(FaultLoop) => {
+                            // The host isn't making progress resolving the faults on this page.
+                            // Stop running the vCPU instead of forwarding the same fault forever.
+                            break VmExitCause::UnhandledTrap(
+                                Trap::Exception(exception).to_scause(),
+                            );
+                        }
+                    };
                     use PageFaultType::*;
                     match pf {
                         Confidential | Shared | Imsic => {
@@ -1390,6 +1402,14 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
 
         // Get the pages we're going to be copying to and inserting.
         let from_page_addr = self.guest_addr_from_raw(dest_addr)?;
+        let src_page_addr = self.guest_addr_from_raw(src_addr)?;
+        // The source data can't come from the pages being donated: they're converted and about to
+        // be handed to the guest, so they can't be read through our address space.
+        if PageAddrRange::new(src_page_addr, num_pages)
+            .overlaps(&PageAddrRange::new(from_page_addr, num_pages))
+        {
+            return Err(EcallError::Sbi(SbiError::InvalidParam));
+        }
         let pages = self
             .vm_pages()
             .get_converted_pages(from_page_addr, num_pages)
@@ -1404,7 +1424,6 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
 
         // Make sure we can initialize the full set of pages before we start actually inserting
         // them into the destination page table.
-        let src_page_addr = self.guest_addr_from_raw(src_addr)?;
         let mut initialized_pages = LockedPageList::new(self.page_tracker());
         for (page, addr) in pages.zip(src_page_addr.iter_from()) {
             match page.try_initialize(|bytes| active_pages.copy_from_guest(bytes, addr.into())) {
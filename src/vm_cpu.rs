@@ -7,7 +7,7 @@ use core::{mem::size_of, ptr::NonNull};
 use drivers::{imsic::*, CpuId, CpuInfo, MAX_CPUS};
 use memoffset::offset_of;
 use page_tracking::collections::PageBox;
-use page_tracking::TlbVersion;
+use page_tracking::{FaultHistory, TlbVersion};
 use riscv_page_tables::GuestStagePagingMode;
 use riscv_pages::{GuestPhysAddr, GuestVirtAddr, PageOwnerId, RawAddr};
 use riscv_regs::*;
@@ -18,7 +18,7 @@ use crate::smp::PerCpu;
 use crate::vm::{MmioOpcode, MmioOperation, VmExitCause};
 use crate::vm_id::VmId;
 use crate::vm_interrupts::{self, VmCpuExtInterrupts};
-use crate::vm_pages::{ActiveVmPages, FinalizedVmPages, PinnedPages};
+use crate::vm_pages::{ActiveVmPages, FinalizedVmPages, PageFaultResult, PinnedPages};
 use crate::vm_pmu::VmPmuState;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -337,6 +337,9 @@ pub enum VmCpuTrap {
         fault_addr: GuestPhysAddr,
         fault_pc: GuestVirtAddr,
         priv_level: PrivilegeLevel,
+        // True if the fault was taken by an implicit access made by the guest's VS-stage page
+        // table walk, rather than by the faulting instruction itself.
+        guest_pt_walk: bool,
     },
     /// Instruction emulation trap.
     VirtualInstruction {
@@ -377,6 +380,7 @@ struct VmCpuArchState {
     prev_tlb: Option<PrevTlb>,
     pending_op: Option<PendingOperation>,
     shmem_area: Option<PinnedTsmShmemArea>,
+    fault_history: FaultHistory,
 }
 
 impl VmCpuArchState {
@@ -414,6 +418,7 @@ impl VmCpuArchState {
             prev_tlb: None,
             pending_op: None,
             shmem_area: None,
+            fault_history: FaultHistory::new(),
         }
     }
 }
@@ -671,6 +676,7 @@ impl<'vcpu, 'pages, 'host, T: GuestStagePagingMode> ActiveVmCpu<'vcpu, 'pages, '
                     // mode into account.
                     fault_pc: RawAddr::guest_virt(regs.guest_regs.sepc, guest_id),
                     priv_level: PrivilegeLevel::from_hstatus(regs.guest_regs.hstatus),
+                    guest_pt_walk: is_implicit_pt_access(regs.trap_csrs.htinst),
                 }
             }
             Trap::Exception(VirtualInstruction) => {
@@ -1007,6 +1013,24 @@ impl<'vcpu, 'pages, 'host, T: GuestStagePagingMode> ActiveVmCpu<'vcpu, 'pages, '
         self.active_pages.as_ref().unwrap()
     }
 
+    /// Handles a guest page fault taken by this vCPU, as described in
+    /// `ActiveVmPages::handle_page_fault()`, using this vCPU's own fault history for fault loop
+    /// detection.
+    pub fn handle_page_fault(
+        &mut self,
+        exception: Exception,
+        fault_addr: GuestPhysAddr,
+        guest_pt_walk: bool,
+    ) -> PageFaultResult {
+        // Unwrap ok: see `active_pages()`.
+        self.active_pages.as_ref().unwrap().handle_page_fault(
+            exception,
+            fault_addr,
+            guest_pt_walk,
+            &mut self.arch.fault_history,
+        )
+    }
+
     /// Performs any pending TLB maintenance for this VM's address space.
     pub fn sync_tlb(&mut self) {
         // Exit and re-enter so that we pick up the new TLB version.
@@ -7,12 +7,14 @@ use arrayvec::ArrayVec;
 use attestation::AttestationManager;
 use core::arch::global_asm;
 use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU64, Ordering};
 use drivers::{imsic::*, iommu::*, pci::PciBarPage, pci::PciDevice, pci::PcieRoot};
 use page_tracking::{
-    LockedPageList, PageList, PageTracker, PageTrackingError, TlbVersion, MAX_PAGE_OWNERS,
+    FaultHistory, LockedPageList, PageFaultType, PageList, PageTracker, PageTrackingError,
+    TlbVersion, MAX_PAGE_OWNERS,
 };
 use riscv_page_tables::{
-    tlb, GuestStageMapper, GuestStagePageTable, GuestStagePagingMode, PageTableError,
+    tlb, AccessType, GuestStageMapper, GuestStagePageTable, GuestStagePagingMode, PageTableError,
 };
 use riscv_pages::*;
 use riscv_regs::{
@@ -33,6 +35,7 @@ pub enum Error {
     NestingTooDeep,
     UnalignedAddress,
     UnsupportedPageSize(PageSize),
+    PteNotOwned(SupervisorPageAddr),
     AddressOverflow,
     TlbCountUnderflow,
     InvalidTlbVersion,
@@ -58,6 +61,18 @@ pub enum Error {
 
 pub type Result<T> = core::result::Result<T, Error>;
 
+impl From<PageTableError> for Error {
+    fn from(error: PageTableError) -> Error {
+        Error::Paging(error)
+    }
+}
+
+impl From<PageTrackingError> for Error {
+    fn from(error: PageTrackingError) -> Error {
+        Error::PageTracker(error)
+    }
+}
+
 #[derive(Debug)]
 pub enum InstructionFetchError {
     FailedDecode(u32),
@@ -66,6 +81,12 @@ pub enum InstructionFetchError {
 
 pub type InstructionFetchResult = core::result::Result<DecodedInstruction, InstructionFetchError>;
 
+/// The only way handling a guest page fault can fail: the vCPU keeps faulting on the same page.
+#[derive(Debug)]
+pub struct FaultLoop;
+
+pub type PageFaultResult = core::result::Result<Option<PageFaultType>, FaultLoop>;
+
 global_asm!(include_str!("guest_mem.S"));
 
 // The copy to/from guest memory routines defined in guest_mem.S.
@@ -647,8 +668,7 @@ impl<'a, T: GuestStagePagingMode, M> VmPagesMapper<'a, T, M> {
         }
         let mapper = vm_pages
             .root
-            .remap_range(page_addr, PageSize::Size4k, num_pages)
-            .map_err(Error::Paging)?;
+            .remap_range(page_addr, PageSize::Size4k, num_pages)?;
         Ok(Self {
             vm_pages,
             mapper,
@@ -784,24 +804,6 @@ impl<'a, T: GuestStagePagingMode> PciPagesMapper<'a, T> {
     }
 }
 
-/// The possible sources of a guest page fault.
-#[derive(Clone, Copy, Debug)]
-pub enum PageFaultType {
-    /// A page fault taken when accessing a confidential memory region. The host may handle these
-    /// faults by inserting a confidential page into the guest's address space.
-    Confidential,
-    /// A page fault taken when accessing a shared memory region. The host may handle these faults
-    /// by inserting a page into the guest's address space.
-    Shared,
-    /// A page fault taken to an emulated MMIO page.
-    Mmio,
-    /// A page fault taken to an IMSIC guest interrupt file page.
-    Imsic,
-    /// A page fault taken when accessing memory outside of any valid region of guest physical
-    /// address space. These faults are not resolvable.
-    Unmapped,
-}
-
 /// Represents the active VM address space. Holds a reference to the TLB version of the address space
 /// at the time the address space was activated. Used to directly access a guest's memory.
 pub struct ActiveVmPages<'a, T: GuestStagePagingMode> {
@@ -865,48 +867,77 @@ impl<'a, T: GuestStagePagingMode> ActiveVmPages<'a, T> {
     /// Copies from `src` to the guest physical address in `dest`. Returns an error if a fault was
     /// encountered while copying.
     pub fn copy_to_guest(&self, dest: GuestPhysAddr, src: &[u8]) -> Result<()> {
-        // Need to disable any translation in VSATP since we're dealing with guest physical addresses.
-        let old_vsatp = CSR.vsatp.atomic_replace(0);
-        // Safety: _copy_to_guest internally detects and handles an invalid guest physical
-        // address in `dest`.
-        let bytes = unsafe { _copy_to_guest(dest.bits(), src.as_ptr(), src.len()) };
-        CSR.vsatp.set(old_vsatp);
-        if bytes == src.len() {
-            Ok(())
-        } else {
+        let mut copied = 0;
+        // A fault on clear A/D bits happens at most once per page, which bounds the retries.
+        let mut retries = pages_spanned(dest, src.len());
+        loop {
+            let to = dest
+                .checked_increment(copied as u64)
+                .ok_or(Error::AddressOverflow)?;
+            let remaining = &src[copied..];
+            // Need to disable any translation in VSATP since we're dealing with guest physical
+            // addresses.
+            let old_vsatp = CSR.vsatp.atomic_replace(0);
+            // Safety: _copy_to_guest internally detects and handles an invalid guest physical
+            // address in `to`.
+            let bytes = unsafe { _copy_to_guest(to.bits(), remaining.as_ptr(), remaining.len()) };
+            CSR.vsatp.set(old_vsatp);
+            copied += bytes;
+            if copied == src.len() {
+                return Ok(());
+            }
             let fault_addr = dest
-                .checked_increment(bytes as u64)
+                .checked_increment(copied as u64)
                 .ok_or(Error::AddressOverflow)?;
-            let fault_type = self.get_page_fault_cause(Exception::GuestStorePageFault, fault_addr);
-            Err(Error::PageFault(
-                fault_type,
-                Exception::GuestStorePageFault,
-                fault_addr,
-            ))
+            if retries == 0 || !self.resolve_accessed_dirty(fault_addr, AccessType::Store) {
+                let fault_type =
+                    self.get_page_fault_cause(Exception::GuestStorePageFault, fault_addr);
+                return Err(Error::PageFault(
+                    fault_type,
+                    Exception::GuestStorePageFault,
+                    fault_addr,
+                ));
+            }
+            retries -= 1;
         }
     }
 
     /// Copies from the guest physical address in `src` to `dest`. Returns an error if a fault was
     /// encountered while copying.
     pub fn copy_from_guest(&self, dest: &mut [u8], src: GuestPhysAddr) -> Result<()> {
-        // Need to disable any translation in VSATP since we're dealing with guest physical addresses.
-        let old_vsatp = CSR.vsatp.atomic_replace(0);
-        // Safety: _copy_from_guest internally detects and handles an invalid guest physical address
-        // in `src`.
-        let bytes = unsafe { _copy_from_guest(dest.as_mut_ptr(), src.bits(), dest.len()) };
-        CSR.vsatp.set(old_vsatp);
-        if bytes == dest.len() {
-            Ok(())
-        } else {
+        let mut copied = 0;
+        // A fault on a clear A bit happens at most once per page, which bounds the retries.
+        let mut retries = pages_spanned(src, dest.len());
+        loop {
+            let from = src
+                .checked_increment(copied as u64)
+                .ok_or(Error::AddressOverflow)?;
+            let remaining = &mut dest[copied..];
+            // Need to disable any translation in VSATP since we're dealing with guest physical
+            // addresses.
+            let old_vsatp = CSR.vsatp.atomic_replace(0);
+            // Safety: _copy_from_guest internally detects and handles an invalid guest physical
+            // address in `from`.
+            let bytes =
+                unsafe { _copy_from_guest(remaining.as_mut_ptr(), from.bits(), remaining.len()) };
+            CSR.vsatp.set(old_vsatp);
+            copied += bytes;
+            if copied == dest.len() {
+                return Ok(());
+            }
             let fault_addr = src
-                .checked_increment(bytes as u64)
+                .checked_increment(copied as u64)
                 .ok_or(Error::AddressOverflow)?;
-            let fault_type = self.get_page_fault_cause(Exception::GuestLoadPageFault, fault_addr);
-            Err(Error::PageFault(
-                fault_type,
-                Exception::GuestLoadPageFault,
-                fault_addr,
-            ))
+            if retries == 0 || !self.resolve_accessed_dirty(fault_addr, AccessType::Load) {
+                let fault_type =
+                    self.get_page_fault_cause(Exception::GuestLoadPageFault, fault_addr);
+                return Err(Error::PageFault(
+                    fault_type,
+                    Exception::GuestLoadPageFault,
+                    fault_addr,
+                ));
+            }
+            retries -= 1;
         }
     }
 
@@ -957,6 +988,76 @@ impl<'a, T: GuestStagePagingMode> ActiveVmPages<'a, T> {
             _ => Unmapped,
         }
     }
+
+    /// Same as `get_page_fault_cause()`, but also records faults that need to be resolved by the
+    /// VM's host in the faulting vCPU's `fault_history`. Returns `FaultLoop` if the vCPU keeps
+    /// faulting on the same page without the VM's address space being updated, in which case the
+    /// vCPU should no longer be run.
+    ///
+    /// `guest_pt_walk` indicates that the fault was taken while walking the guest's own VS-stage
+    /// page table, see `PageFaultType::for_guest_pt_walk()`.
+    ///
+    /// On platforms where the hardware doesn't update the A and D bits of guest-stage PTEs, the
+    /// first access to a page, or first store to it, faults. Those faults are resolved here by
+    /// setting the bits, in which case `None` is returned and the access can simply be retried.
+    pub fn handle_page_fault(
+        &self,
+        exception: Exception,
+        fault_addr: GuestPhysAddr,
+        guest_pt_walk: bool,
+        fault_history: &mut FaultHistory,
+    ) -> PageFaultResult {
+        use PageFaultType::*;
+        if let Some(access) = fault_access_type(exception)
+            && self.resolve_accessed_dirty(fault_addr, access)
+        {
+            return Ok(None);
+        }
+        let mut pf = self.get_page_fault_cause(exception, fault_addr);
+        if guest_pt_walk {
+            pf = pf.for_guest_pt_walk();
+        }
+        if matches!(pf, Confidential | Shared | Imsic)
+            && fault_history.record(
+                PageAddr::with_round_down(fault_addr, PageSize::Size4k),
+                self.vm_pages.inner.map_generation.load(Ordering::Relaxed),
+            )
+        {
+            return Err(FaultLoop);
+        }
+        Ok(Some(pf))
+    }
+
+    // Sets the A/D bits for a guest `access` at `fault_addr` if it faulted only because they were
+    // clear, returning true if the access can now be retried.
+    fn resolve_accessed_dirty(&self, fault_addr: GuestPhysAddr, access: AccessType) -> bool {
+        if !self
+            .vm_pages
+            .inner
+            .root
+            .update_accessed_dirty(fault_addr, access)
+        {
+            return false;
+        }
+        // The translation that faulted may still be cached on this CPU.
+        tlb::hfence_gvma(Some(fault_addr.bits()), None);
+        true
+    }
+}
+
+// Returns the number of 4kB pages touched by an access of `len` bytes at `addr`.
+fn pages_spanned(addr: GuestPhysAddr, len: usize) -> u64 {
+    PageSize::num_4k_pages(addr.bits() % PageSize::Size4k as u64 + len as u64)
+}
+
+// Returns the type of guest access that raised the guest page fault `exception`.
+fn fault_access_type(exception: Exception) -> Option<AccessType> {
+    match exception {
+        Exception::GuestInstructionPageFault => Some(AccessType::Fetch),
+        Exception::GuestLoadPageFault => Some(AccessType::Load),
+        Exception::GuestStorePageFault => Some(AccessType::Store),
+        _ => None,
+    }
 }
 
 /// A pool of page-table pages for a VM. Left over pages are released when the pool is dropped.
@@ -1060,6 +1161,8 @@ pub struct VmPages<T: GuestStagePagingMode> {
     pte_pages: PtePagePool,
     imsic_geometry: Once<GuestImsicGeometry>,
     iommu_context: Once<VmIommuContext>,
+    // Bumped whenever pages are mapped, for detecting vCPUs that stop making progress on faults.
+    map_generation: AtomicU64,
 }
 
 impl<T: GuestStagePagingMode> VmPages<T> {
@@ -1076,6 +1179,7 @@ impl<T: GuestStagePagingMode> VmPages<T> {
             pte_pages: PtePagePool::new(page_tracker),
             imsic_geometry: Once::new(),
             iommu_context: Once::new(),
+            map_generation: AtomicU64::new(0),
         }
     }
 
@@ -1134,11 +1238,19 @@ impl<'a, T: GuestStagePagingMode, S> VmPagesRef<'a, T, S> {
     }
 
     /// Add a page to be used for building the guest's page tables.
-    /// Currently only supports 4k pages.
+    /// Currently only supports 4k pages. The page must already be assigned to this VM as an
+    /// internal state page.
     pub fn add_pte_page(&self, page: Page<InternalClean>) -> Result<()> {
         if page.size() != PageSize::Size4k {
             return Err(Error::UnsupportedPageSize(page.size()));
         }
+        if !self
+            .inner
+            .page_tracker
+            .is_internal_state_page(page.addr(), self.page_owner_id())
+        {
+            return Err(Error::PteNotOwned(page.addr()));
+        }
         self.inner.pte_pages.push(page);
         Ok(())
     }
@@ -1152,7 +1264,9 @@ impl<'a, T: GuestStagePagingMode, S> VmPagesRef<'a, T, S> {
         if count == 0 {
             return Err(Error::EmptyPageRange);
         }
-        VmPagesMapper::new_in_region(self.inner, page_addr, count, region_type)
+        let mapper = VmPagesMapper::new_in_region(self.inner, page_addr, count, region_type)?;
+        self.inner.map_generation.fetch_add(1, Ordering::Relaxed);
+        Ok(mapper)
     }
 
     fn do_remap_pages<M>(
@@ -1164,7 +1278,10 @@ impl<'a, T: GuestStagePagingMode, S> VmPagesRef<'a, T, S> {
         if count == 0 {
             return Err(Error::EmptyPageRange);
         }
-        VmPagesMapper::new_in_region_mapped(self.inner, page_addr, count, region_type)
+        let mapper =
+            VmPagesMapper::new_in_region_mapped(self.inner, page_addr, count, region_type)?;
+        self.inner.map_generation.fetch_add(1, Ordering::Relaxed);
+        Ok(mapper)
     }
 
     /// Same as `map_zero_pages()`, but for IMSIC guest interrupt file pages.
@@ -1280,8 +1397,7 @@ impl<'a, T: GuestStagePagingMode> FinalizedVmPages<'a, T> {
                 self.inner
                     .page_tracker
                     .is_mapped_page(addr, self.inner.page_owner_id, MemType::Ram)
-            })
-            .map_err(Error::Paging)?;
+            })?;
         let version = self.inner.tlb_tracker.current_version();
         let mut num_pages = 0;
         for paddr in invalidated {
@@ -1359,8 +1475,7 @@ impl<'a, T: GuestStagePagingMode> FinalizedVmPages<'a, T> {
                         .inner
                         .page_tracker
                         .is_owned(addr, self.inner.page_owner_id)
-            })
-            .map_err(Error::Paging)?
+            })?
             .count();
 
         // If the range was populated we need a TLB flush before the conversion to confidential can
@@ -1505,8 +1620,7 @@ impl<'a, T: GuestStagePagingMode> FinalizedVmPages<'a, T> {
                     self.inner.page_owner_id,
                     MemType::Ram,
                 )
-            })
-            .map_err(Error::Paging)?
+            })?
             .map(|addr| {
                 self.inner
                     .page_tracker
@@ -1705,8 +1819,7 @@ impl<'a, T: GuestStagePagingMode> FinalizedVmPages<'a, T> {
     pub fn unassign_imsic_page_begin(&self, page: ImsicGuestPage<Invalidated>) -> Result<()> {
         self.inner
             .page_tracker
-            .unassign_page_begin(page, self.inner.tlb_tracker.current_version())
-            .map_err(Error::PageTracker)?;
+            .unassign_page_begin(page, self.inner.tlb_tracker.current_version())?;
         Ok(())
     }
 
@@ -1770,8 +1883,7 @@ impl<'a, T: GuestStagePagingMode> FinalizedVmPages<'a, T> {
                         .page_tracker
                         .is_shareable_page(addr, self.inner.page_owner_id, MemType::Ram)
                 }
-            })
-            .map_err(Error::Paging)?
+            })?
             .map(|addr| {
                 self.inner
                     .page_tracker
@@ -1975,3 +2087,29 @@ impl<'a, T: GuestStagePagingMode> From<InitializingVmPages<'a, T>> for AnyVmPage
         VmPagesRef::new(src.inner)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_conversions() {
+        fn map() -> Result<()> {
+            Err(PageTableError::MappingExists)?;
+            Ok(())
+        }
+        assert!(matches!(
+            map(),
+            Err(Error::Paging(PageTableError::MappingExists))
+        ));
+
+        fn track() -> Result<()> {
+            Err(PageTrackingError::OwnerOverflow)?;
+            Ok(())
+        }
+        assert!(matches!(
+            track(),
+            Err(Error::PageTracker(PageTrackingError::OwnerOverflow))
+        ));
+    }
+}